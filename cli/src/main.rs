@@ -408,35 +408,48 @@ fn main() -> std::result::Result<(), ClientError> {
 
             let rs = magik_client
                 .request()
-                .accounts(magik_program::accounts::Init {
+                .accounts(magik_program::accounts::CreateVault {
                     vault,
                     vault_token,
                     synth_mint,
                     mint_token,
                     authority: authority.pubkey(),
-                    obligation,
                     lending_market,
                     system_program: system_program::id(),
                     lending_program,
                     token_program: spl_token::ID,
-                    clock: sysvar::clock::ID,
                     rent: sysvar::rent::ID,
                 })
-                .args(magik_program::instruction::Init {
+                .args(magik_program::instruction::CreateVault {
                     param: magik_program::state::InitParam {
                         bump: magik_program::state::Bump {
                             mint_bump,
                             token_bump,
                             vault_bump,
                         },
-                        init_obligation: true,
                         percent: 40,
                     },
-                    ob_bump,
-                    nonce,
                 })
                 .signer(&authority)
                 .send();
+            println!("TX magik_client CREATE VAULT: {:?}", rs);
+
+            let rs = magik_client
+                .request()
+                .accounts(magik_program::accounts::CreateObligation {
+                    vault,
+                    payer: authority.pubkey(),
+                    obligation,
+                    lending_market,
+                    lending_program,
+                    token_program: spl_token::ID,
+                    system_program: system_program::id(),
+                    rent: sysvar::rent::ID,
+                    clock: sysvar::clock::ID,
+                })
+                .args(magik_program::instruction::CreateObligation { nonce, ob_bump })
+                .signer(&authority)
+                .send();
             println!("TX magik_client INIT: {:?} obligation {}", rs, obligation);
             assert_eq!(rs.is_err(), false);
         }
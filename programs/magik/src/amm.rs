@@ -0,0 +1,41 @@
+use anchor_lang::prelude::*;
+use solana_program::instruction::Instruction;
+use solana_program::program::invoke;
+
+// SPL Token Swap `Swap`, the layout the configured AMM has to accept
+pub const SWAP: u8 = 1;
+
+/// Swaps `amount_in` through `amm_program`.
+///
+/// `accounts` are handed to the AMM as is, in the order of a Token Swap `Swap`: swap, swap
+/// authority, user transfer authority, source, swap source, swap destination, destination, pool
+/// mint, pool fee account and token program.
+pub fn swap<'info>(
+    amm_program: &AccountInfo<'info>,
+    accounts: &[AccountInfo<'info>],
+    amount_in: u64,
+    minimum_amount_out: u64,
+) -> ProgramResult {
+    let mut data = vec![SWAP];
+    data.extend_from_slice(&amount_in.to_le_bytes());
+    data.extend_from_slice(&minimum_amount_out.to_le_bytes());
+
+    let ix = Instruction {
+        program_id: amm_program.key(),
+        accounts: accounts
+            .iter()
+            .map(|account| {
+                if account.is_writable {
+                    AccountMeta::new(account.key(), account.is_signer)
+                } else {
+                    AccountMeta::new_readonly(account.key(), account.is_signer)
+                }
+            })
+            .collect(),
+        data,
+    };
+
+    let mut infos = accounts.to_vec();
+    infos.push(amm_program.clone());
+    invoke(&ix, &infos)
+}
@@ -0,0 +1,35 @@
+use anchor_lang::prelude::*;
+use solana_program::sysvar::instructions::{
+    load_current_index_checked, load_instruction_at_checked,
+};
+
+use crate::VaultError;
+
+/// Makes sure the executing instruction is called directly by the transaction, and that a later
+/// instruction of this program starts with `repay_data` and has `vault` as its first account.
+///
+/// Flash loans and mints use it to refuse going out unless they're settled before the transaction
+/// ends. Called through CPI, the current top-level instruction belongs to another program and
+/// the check fails.
+pub fn require_repayment(
+    instructions: &AccountInfo,
+    vault: &Pubkey,
+    repay_data: &[u8],
+) -> ProgramResult {
+    let current = load_current_index_checked(instructions)? as usize;
+    if load_instruction_at_checked(current, instructions)?.program_id != crate::ID {
+        return Err(VaultError::InvalidFlashLoan.into());
+    }
+
+    let mut index = current + 1;
+    while let Ok(ix) = load_instruction_at_checked(index, instructions) {
+        if ix.program_id == crate::ID
+            && ix.data.starts_with(repay_data)
+            && ix.accounts.first().map(|meta| meta.pubkey) == Some(*vault)
+        {
+            return Ok(());
+        }
+        index += 1;
+    }
+    Err(VaultError::FlashLoanNotRepaid.into())
+}
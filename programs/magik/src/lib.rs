@@ -1,17 +1,30 @@
 #![allow(unused)]
+pub mod amm;
+pub mod flash;
+pub mod metadata;
 mod parameters;
 pub mod port;
+pub mod position;
+pub mod rewards;
+pub mod stability;
 pub mod state;
+pub mod token_2022;
+pub mod valuation;
 
 use anchor_lang::prelude::*;
 use anchor_lang::solana_program::{pubkey::Pubkey, system_program, sysvar};
-use anchor_spl::token::{self, Burn, Mint, MintTo, TokenAccount, Transfer};
+use anchor_lang::{Discriminator, InstructionData};
+use anchor_spl::token::{
+    self, Burn, CloseAccount, Mint, MintTo, SetAuthority, TokenAccount, Transfer,
+};
 use port::*;
 use port_variable_rate_lending_instructions::instruction::LendingInstruction;
 use solana_program::instruction::Instruction;
-use solana_program::program::{invoke, invoke_signed};
+use solana_program::program::{invoke, invoke_signed, set_return_data};
 use solana_program::program_option::COption;
+use solana_program::program_pack::Pack;
 use state::*;
+use std::mem::size_of;
 
 use crate::parameters::Parameters;
 declare_id!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnS");
@@ -20,113 +33,942 @@ pub mod magik {
     use solana_program::{program::invoke_signed, system_instruction::create_account};
 
     use super::*;
-    pub fn init(ctx: Context<Init>, param: InitParam, nonce: Pubkey, ob_bump: u8) -> ProgramResult {
+    pub fn create_vault(ctx: Context<CreateVault>, param: InitParam) -> ProgramResult {
         msg!("Init params {:?}", param);
         Parameters::verify_percent(param.percent);
-        {
-            let ref mut vault = ctx.accounts.vault;
-            vault.bump = param.bump.vault_bump;
-            vault.mint_token = ctx.accounts.mint_token.key();
-            vault.vault_token = ctx.accounts.vault_token.key();
-            vault.synth_token = ctx.accounts.synth_mint.key();
-            vault.payer = ctx.accounts.authority.key();
-
-            vault.percent = param.percent;
-
-            emit!(InitVault {
-                mint_token: vault.mint_token,
-                vault_token: vault.vault_token,
-                synth_token: vault.synth_token,
-                payer: vault.payer,
-                percent: vault.percent,
-            });
+        let vault = &mut ctx.accounts.vault;
+        vault.bump = param.bump.vault_bump;
+        vault.mint_token = ctx.accounts.mint_token.key();
+        vault.vault_token = ctx.accounts.vault_token.key();
+        vault.synth_token = ctx.accounts.synth_mint.key();
+        vault.payer = ctx.accounts.authority.key();
+        vault.lending_program = ctx.accounts.lending_program.key();
+        vault.lending_market = ctx.accounts.lending_market.key();
+
+        vault.token_program = spl_token::ID;
+
+        vault.percent = param.percent;
+        vault.version = Vault::VERSION;
+        vault.counts_positions = true;
+
+        emit!(InitVault {
+            mint_token: vault.mint_token,
+            vault_token: vault.vault_token,
+            synth_token: vault.synth_token,
+            payer: vault.payer,
+            percent: vault.percent,
+        });
+
+        Ok(())
+    }
+
+    /// Same as `create_vault` for a Token-2022 `mint_token`, the synth is still a plain SPL mint.
+    pub fn create_vault_2022(
+        ctx: Context<CreateVault2022>,
+        param: InitParam,
+        decimals: u8,
+    ) -> ProgramResult {
+        msg!("Init params {:?}", param);
+        Parameters::verify_percent(param.percent);
+        let mint = token_2022::unpack_mint(&ctx.accounts.mint_token)?;
+        if mint.decimals != decimals {
+            return Err(VaultError::InvalidTokenAccount.into());
         }
 
-        if param.init_obligation {
-            let ref vault = ctx.accounts.vault;
-            let vault_key = ctx.accounts.vault.clone().key();
-            let cpi_account = InitObligation {
-                clock: ctx.accounts.clock.to_account_info(),
-                lending_market: ctx.accounts.lending_market.to_account_info(),
-                obligation: ctx.accounts.obligation.to_account_info(),
-                obligation_owner: ctx.accounts.vault.clone().to_account_info(),
-                rent: ctx.accounts.rent.to_account_info(),
-                spl_token_id: ctx.accounts.token_program.to_account_info(),
-            };
+        let mint_token = ctx.accounts.mint_token.key();
+        let vault_key = ctx.accounts.vault.key();
+        let seeds = &[
+            b"vault_token".as_ref(),
+            mint_token.as_ref(),
+            vault_key.as_ref(),
+            &[param.bump.token_bump],
+        ];
+        let signer_seeds = &[&seeds[..]];
+        let create_ctx = CpiContext::new_with_signer(
+            ctx.accounts.mint_token_program.to_account_info(),
+            token_2022::CreateTokenAccount {
+                payer: ctx.accounts.authority.to_account_info(),
+                account: ctx.accounts.vault_token.to_account_info(),
+                mint: ctx.accounts.mint_token.to_account_info(),
+                owner: ctx.accounts.vault.to_account_info(),
+                system_program: ctx.accounts.system_program.clone(),
+            },
+            signer_seeds,
+        );
+        token_2022::create_account_for_mint(create_ctx, &ctx.accounts.rent)?;
 
-            let lending_program = ctx.accounts.lending_program.to_account_info();
+        let vault = &mut ctx.accounts.vault;
+        vault.bump = param.bump.vault_bump;
+        vault.mint_token = mint_token;
+        vault.vault_token = ctx.accounts.vault_token.key();
+        vault.synth_token = ctx.accounts.synth_mint.key();
+        vault.payer = ctx.accounts.authority.key();
+        vault.lending_program = ctx.accounts.lending_program.key();
+        vault.lending_market = ctx.accounts.lending_market.key();
+        vault.token_program = token_2022::token_2022_program::ID;
 
-            let lending_program_id = lending_program.key;
-            let seeds = &[
-                b"obligation".as_ref(),
-                nonce.as_ref(),
-                vault_key.as_ref(),
-                &[ob_bump],
-            ];
-            let signers_seeds = &[&seeds[..]];
-            invoke_signed(
-                &create_account(
-                    &ctx.accounts.authority.key,
-                    &ctx.accounts.obligation.key,
-                    7266240,
-                    916,
-                    lending_program.key,
-                ),
-                &[
-                    ctx.accounts.authority.to_account_info(),
-                    ctx.accounts.obligation.to_account_info(),
-                ],
-                signers_seeds,
-            )?;
+        vault.percent = param.percent;
+        vault.version = Vault::VERSION;
+        vault.counts_positions = true;
 
-            let vault_seeds = &[
-                b"vault".as_ref(),
-                ctx.accounts.vault.mint_token.as_ref(),
-                ctx.accounts.vault.payer.as_ref(),
-                &[ctx.accounts.vault.bump],
-            ];
-            let vault_signer_seeds = &[&vault_seeds[..]];
+        emit!(InitVault {
+            mint_token: vault.mint_token,
+            vault_token: vault.vault_token,
+            synth_token: vault.synth_token,
+            payer: vault.payer,
+            percent: vault.percent,
+        });
 
-            let init_obligation_ctx =
-                CpiContext::new_with_signer(lending_program, cpi_account, vault_signer_seeds);
+        Ok(())
+    }
 
-            init_obligation(lending_program_id, init_obligation_ctx)?;
-        }
+    pub fn create_obligation(
+        ctx: Context<CreateObligation>,
+        nonce: Pubkey,
+        ob_bump: u8,
+    ) -> ProgramResult {
+        msg!("Create obligation {}", ctx.accounts.obligation.key());
+        let vault = &ctx.accounts.vault;
+        let vault_key = ctx.accounts.vault.clone().key();
+        let cpi_account = InitObligation {
+            clock: ctx.accounts.clock.to_account_info(),
+            lending_market: ctx.accounts.lending_market.to_account_info(),
+            obligation: ctx.accounts.obligation.to_account_info(),
+            obligation_owner: ctx.accounts.vault.clone().to_account_info(),
+            rent: ctx.accounts.rent.to_account_info(),
+            spl_token_id: ctx.accounts.token_program.to_account_info(),
+        };
 
+        let lending_program = ctx.accounts.lending_program.to_account_info();
+
+        let lending_program_id = lending_program.key;
+        let seeds = &[
+            b"obligation".as_ref(),
+            nonce.as_ref(),
+            vault_key.as_ref(),
+            &[ob_bump],
+        ];
+        let signers_seeds = &[&seeds[..]];
+        invoke_signed(
+            &create_account(
+                ctx.accounts.payer.key,
+                ctx.accounts.obligation.key,
+                7266240,
+                916,
+                lending_program.key,
+            ),
+            &[
+                ctx.accounts.payer.to_account_info(),
+                ctx.accounts.obligation.to_account_info(),
+            ],
+            signers_seeds,
+        )?;
+
+        let vault_seeds = &[
+            b"vault".as_ref(),
+            ctx.accounts.vault.mint_token.as_ref(),
+            ctx.accounts.vault.payer.as_ref(),
+            &[ctx.accounts.vault.bump],
+        ];
+        let vault_signer_seeds = &[&vault_seeds[..]];
+
+        let init_obligation_ctx =
+            CpiContext::new_with_signer(lending_program, cpi_account, vault_signer_seeds);
+
+        init_obligation(lending_program_id, init_obligation_ctx)?;
+
+        let vault = &mut ctx.accounts.vault;
+        vault.obligation = ctx.accounts.obligation.key();
+        emit!(CreateObligationEvent {
+            vault: vault.key(),
+            obligation: vault.obligation,
+        });
         Ok(())
     }
 
     pub fn update_vault(ctx: Context<UpdateVault>, percent: u64) -> ProgramResult {
         msg!("update_vault {}", percent);
         Parameters::verify_percent(percent);
-        let ref mut vault = ctx.accounts.vault;
+        let vault = &mut ctx.accounts.vault;
+        if vault.timelock_delay != 0 {
+            return Err(VaultError::TimelockActive.into());
+        }
         vault.percent = percent;
+        emit!(UpdateVaultEvent {
+            vault: vault.key(),
+            percent,
+        });
         Ok(())
     }
 
-    pub fn deposit(ctx: Context<Deposit>, bump: u8, amount: u64) -> ProgramResult {
-        msg!("Deposit {}", amount);
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.user_token.to_account_info().clone(),
-            to: ctx.accounts.vault_token.to_account_info().clone(),
-            authority: ctx.accounts.owner.clone(),
+    pub fn update_fees(
+        ctx: Context<UpdateFees>,
+        borrow_fee_bps: u64,
+        interest_rate: u64,
+    ) -> ProgramResult {
+        msg!("update_fees {} {}", borrow_fee_bps, interest_rate);
+        Parameters::verify_fees(borrow_fee_bps, interest_rate);
+        let vault = &mut ctx.accounts.vault;
+        if vault.timelock_delay != 0 {
+            return Err(VaultError::TimelockActive.into());
+        }
+        // Interest up to now is owed at the old rate
+        vault.accrue_interest(ctx.accounts.clock.unix_timestamp)?;
+        vault.borrow_fee_bps = borrow_fee_bps;
+        vault.interest_rate = interest_rate;
+        vault.treasury = ctx.accounts.treasury.key();
+        emit!(UpdateFeesEvent {
+            vault: vault.key(),
+            borrow_fee_bps,
+            interest_rate,
+            treasury: vault.treasury,
+        });
+        Ok(())
+    }
+
+    /// Restricts `deposit` and `borrow` to wallets on the vault's allow-list, or opens them again.
+    pub fn set_permissioned(ctx: Context<SetPermissioned>, permissioned: bool) -> ProgramResult {
+        msg!("set_permissioned {}", permissioned);
+        let vault = &mut ctx.accounts.vault;
+        vault.permissioned = permissioned;
+        emit!(SetPermissionedEvent {
+            vault: vault.key(),
+            permissioned,
+        });
+        Ok(())
+    }
+
+    pub fn allow_wallet(ctx: Context<AllowWallet>, bump: u8) -> ProgramResult {
+        msg!("allow_wallet {}", ctx.accounts.wallet.key());
+        let entry = &mut ctx.accounts.entry;
+        entry.vault = ctx.accounts.vault.key();
+        entry.wallet = ctx.accounts.wallet.key();
+        entry.bump = bump;
+        emit!(AllowListEvent {
+            vault: entry.vault,
+            wallet: entry.wallet,
+            allowed: true,
+        });
+        Ok(())
+    }
+
+    pub fn disallow_wallet(ctx: Context<DisallowWallet>) -> ProgramResult {
+        msg!("disallow_wallet {}", ctx.accounts.entry.wallet);
+        emit!(AllowListEvent {
+            vault: ctx.accounts.vault.key(),
+            wallet: ctx.accounts.entry.wallet,
+            allowed: false,
+        });
+        Ok(())
+    }
+
+    /// Sets how long queued updates wait. Once non zero, `update_vault` and `update_fees` are
+    /// refused and changes go through `queue_update`.
+    pub fn set_timelock(ctx: Context<SetTimelock>, timelock_delay: i64) -> ProgramResult {
+        msg!("set_timelock {}s", timelock_delay);
+        Parameters::verify_timelock_delay(timelock_delay);
+        let vault = &mut ctx.accounts.vault;
+        // Shortening the notice is itself a change depositors get notice of
+        if timelock_delay < vault.timelock_delay {
+            return Err(VaultError::TimelockActive.into());
+        }
+        vault.timelock_delay = timelock_delay;
+        emit!(SetTimelockEvent {
+            vault: vault.key(),
+            timelock_delay,
+        });
+        Ok(())
+    }
+
+    /// Queues a new parameter set, executable once the vault's timelock delay passed.
+    pub fn queue_update(
+        ctx: Context<QueueUpdate>,
+        bump: u8,
+        percent: u64,
+        borrow_fee_bps: u64,
+        interest_rate: u64,
+        timelock_delay: i64,
+    ) -> ProgramResult {
+        msg!(
+            "queue_update {} {} {} {}s",
+            percent,
+            borrow_fee_bps,
+            interest_rate,
+            timelock_delay
+        );
+        Parameters::verify_percent(percent);
+        Parameters::verify_fees(borrow_fee_bps, interest_rate);
+        Parameters::verify_timelock_delay(timelock_delay);
+        let vault = &ctx.accounts.vault;
+        let pending = &mut ctx.accounts.pending_update;
+        pending.vault = vault.key();
+        pending.bump = bump;
+        pending.percent = percent;
+        pending.borrow_fee_bps = borrow_fee_bps;
+        pending.interest_rate = interest_rate;
+        pending.treasury = ctx.accounts.treasury.key();
+        pending.timelock_delay = timelock_delay;
+        pending.eta = ctx
+            .accounts
+            .clock
+            .unix_timestamp
+            .checked_add(vault.timelock_delay)
+            .ok_or(VaultError::MathOverflow)?;
+        emit!(UpdateQueuedEvent {
+            vault: vault.key(),
+            percent,
+            borrow_fee_bps,
+            interest_rate,
+            treasury: pending.treasury,
+            timelock_delay,
+            eta: pending.eta,
+        });
+        Ok(())
+    }
+
+    pub fn execute_update(ctx: Context<ExecuteUpdate>) -> ProgramResult {
+        let pending = &ctx.accounts.pending_update;
+        let now = ctx.accounts.clock.unix_timestamp;
+        msg!("execute_update eta {} now {}", pending.eta, now);
+        if now < pending.eta {
+            return Err(VaultError::TimelockNotExpired.into());
+        }
+        let vault = &mut ctx.accounts.vault;
+        // Interest up to now is owed at the old rate
+        vault.accrue_interest(now)?;
+        vault.percent = pending.percent;
+        vault.borrow_fee_bps = pending.borrow_fee_bps;
+        vault.interest_rate = pending.interest_rate;
+        vault.treasury = pending.treasury;
+        vault.timelock_delay = pending.timelock_delay;
+        emit!(UpdateExecutedEvent {
+            vault: vault.key(),
+            percent: vault.percent,
+            borrow_fee_bps: vault.borrow_fee_bps,
+            interest_rate: vault.interest_rate,
+            treasury: vault.treasury,
+            timelock_delay: vault.timelock_delay,
+        });
+        Ok(())
+    }
+
+    pub fn cancel_update(ctx: Context<CancelUpdate>) -> ProgramResult {
+        msg!("cancel_update {}", ctx.accounts.pending_update.key());
+        emit!(UpdateCancelledEvent {
+            vault: ctx.accounts.vault.key(),
+        });
+        Ok(())
+    }
+
+    pub fn set_mint_limit(
+        ctx: Context<SetMintLimit>,
+        mint_limit: u64,
+        mint_window: i64,
+    ) -> ProgramResult {
+        msg!("set_mint_limit {} per {}s", mint_limit, mint_window);
+        Parameters::verify_mint_window(mint_window);
+        let vault = &mut ctx.accounts.vault;
+        vault.mint_limit = mint_limit;
+        vault.mint_window = mint_window;
+        emit!(SetMintLimitEvent {
+            vault: vault.key(),
+            mint_limit,
+            mint_window,
+        });
+        Ok(())
+    }
+
+    pub fn set_amm(ctx: Context<SetAmm>) -> ProgramResult {
+        msg!("set_amm {}", ctx.accounts.amm_program.key());
+        let vault = &mut ctx.accounts.vault;
+        vault.amm_program = ctx.accounts.amm_program.key();
+        emit!(SetAmmEvent {
+            vault: vault.key(),
+            amm_program: vault.amm_program,
+        });
+        Ok(())
+    }
+
+    pub fn open_position(
+        ctx: Context<OpenPosition>,
+        positions_bump: u8,
+        treasure_bump: u8,
+        index: u64,
+    ) -> ProgramResult {
+        msg!("Open position {}", index);
+        let vault = &mut ctx.accounts.vault;
+        vault.open_positions = vault
+            .open_positions
+            .checked_add(1)
+            .ok_or(VaultError::MathOverflow)?;
+        position::init_treasure(
+            &mut ctx.accounts.user_positions,
+            &mut ctx.accounts.treasure,
+            ctx.accounts.owner.key(),
+            ctx.accounts.vault.key(),
+            positions_bump,
+            treasure_bump,
+            index,
+        )
+    }
+
+    pub fn transfer_position(
+        ctx: Context<TransferPosition>,
+        positions_bump: u8,
+        treasure_bump: u8,
+        index: u64,
+    ) -> ProgramResult {
+        msg!("Transfer position to {}", ctx.accounts.new_owner.key());
+        position::verify_allowed(
+            &ctx.accounts.vault,
+            ctx.accounts.new_owner.key(),
+            ctx.remaining_accounts,
+        )?;
+        position::init_treasure(
+            &mut ctx.accounts.new_user_positions,
+            &mut ctx.accounts.new_treasure,
+            ctx.accounts.new_owner.key(),
+            ctx.accounts.vault.key(),
+            positions_bump,
+            treasure_bump,
+            index,
+        )?;
+
+        // The old PDA is derived from the old owner, so the position moves to a new one
+        let treasure = &ctx.accounts.treasure;
+        let new_treasure = &mut ctx.accounts.new_treasure;
+        new_treasure.current_deposit = treasure.current_deposit;
+        new_treasure.current_borrow = treasure.current_borrow;
+        new_treasure.borrow_index = treasure.borrow_index;
+        new_treasure.reward_per_share = treasure.reward_per_share;
+        new_treasure.reward_owed = treasure.reward_owed;
+        new_treasure.deposit_index = treasure.deposit_index;
+        new_treasure.referrer = treasure.referrer;
+
+        emit!(TransferPositionEvent {
+            vault: new_treasure.vault,
+            from: treasure.owner,
+            from_index: treasure.index,
+            to: new_treasure.owner,
+            to_index: new_treasure.index,
+            deposit: new_treasure.current_deposit,
+            borrow: new_treasure.current_borrow,
+        });
+        Ok(())
+    }
+
+    pub fn tokenize_position(ctx: Context<TokenizePosition>, mint_bump: u8) -> ProgramResult {
+        msg!("Tokenize position {}", ctx.accounts.treasure.key());
+        let seeds = &[
+            b"vault".as_ref(),
+            ctx.accounts.vault.mint_token.as_ref(),
+            ctx.accounts.vault.payer.as_ref(),
+            &[ctx.accounts.vault.bump],
+        ];
+        let signer_seeds = &[&seeds[..]];
+        let mint_to_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.clone(),
+            MintTo {
+                mint: ctx.accounts.position_mint.to_account_info(),
+                to: ctx.accounts.owner_position_token.to_account_info(),
+                authority: ctx.accounts.vault.to_account_info(),
+            },
+            signer_seeds,
+        );
+        token::mint_to(mint_to_ctx, 1)?;
+
+        // Drop the mint authority so the position can never have a second NFT
+        let set_authority_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.clone(),
+            SetAuthority {
+                account_or_mint: ctx.accounts.position_mint.to_account_info(),
+                current_authority: ctx.accounts.vault.to_account_info(),
+            },
+            signer_seeds,
+        );
+        token::set_authority(
+            set_authority_ctx,
+            spl_token::instruction::AuthorityType::MintTokens,
+            None,
+        )?;
+
+        let treasure = &mut ctx.accounts.treasure;
+        treasure.position_mint = ctx.accounts.position_mint.key();
+        Ok(())
+    }
+
+    pub fn set_synth_metadata(
+        ctx: Context<SetSynthMetadata>,
+        name: String,
+        symbol: String,
+        uri: String,
+    ) -> ProgramResult {
+        msg!("Synth metadata {} {}", name, symbol);
+        let (metadata_key, _) = metadata::find_metadata_address(&ctx.accounts.synth_mint.key());
+        if metadata_key != ctx.accounts.metadata.key() {
+            return Err(VaultError::InvalidMetadata.into());
+        }
+
+        let seeds = &[
+            b"vault".as_ref(),
+            ctx.accounts.vault.mint_token.as_ref(),
+            ctx.accounts.vault.payer.as_ref(),
+            &[ctx.accounts.vault.bump],
+        ];
+        let signer_seeds = &[&seeds[..]];
+        let data = metadata::DataV2::synth(name.clone(), symbol.clone(), uri.clone())?;
+        let metadata_program = ctx.accounts.metadata_program.to_account_info();
+
+        // The vault is both mint and update authority of its synth
+        if ctx.accounts.metadata.data_is_empty() {
+            let cpi_ctx = CpiContext::new_with_signer(
+                metadata_program,
+                metadata::CreateMetadata {
+                    metadata: ctx.accounts.metadata.to_account_info(),
+                    mint: ctx.accounts.synth_mint.to_account_info(),
+                    mint_authority: ctx.accounts.vault.to_account_info(),
+                    payer: ctx.accounts.payer.to_account_info(),
+                    update_authority: ctx.accounts.vault.to_account_info(),
+                    system_program: ctx.accounts.system_program.clone(),
+                    rent: ctx.accounts.rent.to_account_info(),
+                },
+                signer_seeds,
+            );
+            metadata::create_metadata(cpi_ctx, data)?;
+        } else {
+            let cpi_ctx = CpiContext::new_with_signer(
+                metadata_program,
+                metadata::UpdateMetadata {
+                    metadata: ctx.accounts.metadata.to_account_info(),
+                    update_authority: ctx.accounts.vault.to_account_info(),
+                },
+                signer_seeds,
+            );
+            metadata::update_metadata(cpi_ctx, data)?;
+        }
+
+        emit!(SynthMetadataEvent {
+            vault: ctx.accounts.vault.key(),
+            name,
+            symbol,
+            uri,
+        });
+        Ok(())
+    }
+
+    pub fn close_vault(ctx: Context<CloseVault>) -> ProgramResult {
+        msg!("Close vault {}", ctx.accounts.vault.key());
+        let seeds = &[
+            b"vault".as_ref(),
+            ctx.accounts.vault.mint_token.as_ref(),
+            ctx.accounts.vault.payer.as_ref(),
+            &[ctx.accounts.vault.bump],
+        ];
+        let signer_seeds = &[&seeds[..]];
+
+        token_2022::check_account(&ctx.accounts.payer_token, &ctx.accounts.vault.mint_token)?;
+
+        // Liquidity still lent through Port would be stranded once the vault is gone
+        if ctx.accounts.vault.collateral != Pubkey::default() {
+            let collateral = TokenAccount::try_deserialize(
+                &mut &ctx.accounts.collateral.try_borrow_data()?[..],
+            )?;
+            if collateral.amount != 0 {
+                return Err(VaultError::VaultNotEmpty.into());
+            }
+        }
+        if ctx.accounts.vault.obligation != Pubkey::default() {
+            let obligation = port_variable_rate_lending_instructions::state::Obligation::unpack(
+                &ctx.accounts.obligation.try_borrow_data()?,
+            )?;
+            if !obligation.deposits.is_empty() || !obligation.borrows.is_empty() {
+                return Err(VaultError::VaultNotEmpty.into());
+            }
+        }
+
+        // Claiming mints synth, so fees still owed to referrers would be lost with the vault
+        if ctx.accounts.vault.referral_accrued != 0 {
+            return Err(VaultError::VaultNotEmpty.into());
+        }
+
+        // No position is left to earn what the reward vault still holds, rewards never emitted
+        // and rounding dust alike
+        let mut swept_reward = 0;
+        if ctx.accounts.vault.reward_vault != Pubkey::default() {
+            let reward_vault = TokenAccount::try_deserialize(
+                &mut &ctx.accounts.reward_vault.try_borrow_data()?[..],
+            )?;
+            swept_reward = reward_vault.amount;
+            if swept_reward > 0 {
+                let payer_reward = TokenAccount::try_deserialize(
+                    &mut &ctx.accounts.payer_reward.try_borrow_data()?[..],
+                )?;
+                if payer_reward.mint != ctx.accounts.vault.reward_mint {
+                    return Err(VaultError::InvalidTokenAccount.into());
+                }
+                sweep_token_account(
+                    ctx.accounts,
+                    ctx.accounts.reward_vault.to_account_info(),
+                    ctx.accounts.payer_reward.to_account_info(),
+                    swept_reward,
+                    signer_seeds,
+                )?;
+            }
+            close_token_account(
+                ctx.accounts,
+                ctx.accounts.reward_vault.to_account_info(),
+                signer_seeds,
+            )?;
+        }
+
+        // Same for the stability pool, collateral gains nobody claimed go to the payer
+        let (pool_key, _) = Pubkey::find_program_address(
+            &[b"stability_pool", ctx.accounts.vault.key().as_ref()],
+            ctx.program_id,
+        );
+        if ctx.accounts.pool.key() != pool_key {
+            return Err(VaultError::VaultNotEmpty.into());
+        }
+        let pool_info = ctx.accounts.pool.to_account_info();
+        let mut swept = 0;
+        if *pool_info.owner == crate::ID {
+            let pool = StabilityPool::try_deserialize(&mut &pool_info.try_borrow_data()?[..])?;
+            if ctx.accounts.pool_synth.key() != pool.pool_synth
+                || ctx.accounts.pool_collateral.key() != pool.pool_collateral
+            {
+                return Err(VaultError::InvalidTokenAccount.into());
+            }
+            // No synth is left anywhere, so pool_synth is empty and total_deposits is only the
+            // dust of scaling deposits by product
+            let pool_synth = TokenAccount::try_deserialize(
+                &mut &ctx.accounts.pool_synth.try_borrow_data()?[..],
+            )?;
+            if pool_synth.amount != 0 {
+                return Err(VaultError::VaultNotEmpty.into());
+            }
+            swept = TokenAccount::try_deserialize(
+                &mut &ctx.accounts.pool_collateral.try_borrow_data()?[..],
+            )?
+            .amount;
+            if swept > 0 {
+                sweep_token_account(
+                    ctx.accounts,
+                    ctx.accounts.pool_collateral.to_account_info(),
+                    ctx.accounts.payer_token.to_account_info(),
+                    swept,
+                    signer_seeds,
+                )?;
+            }
+            close_token_account(
+                ctx.accounts,
+                ctx.accounts.pool_synth.to_account_info(),
+                signer_seeds,
+            )?;
+            close_token_account(
+                ctx.accounts,
+                ctx.accounts.pool_collateral.to_account_info(),
+                signer_seeds,
+            )?;
+        }
+
+        // Leftover yield or dust has no depositor to go to anymore
+        let leftover = token_2022::unpack_account(&ctx.accounts.vault_token)?.amount;
+        if leftover > 0 {
+            let decimals = token_2022::unpack_mint(&ctx.accounts.mint_token)?.decimals;
+            let transfer_ctx = CpiContext::new_with_signer(
+                ctx.accounts.mint_token_program.to_account_info(),
+                token_2022::TransferChecked {
+                    from: ctx.accounts.vault_token.to_account_info(),
+                    mint: ctx.accounts.mint_token.to_account_info(),
+                    to: ctx.accounts.payer_token.to_account_info(),
+                    authority: ctx.accounts.vault.to_account_info(),
+                },
+                signer_seeds,
+            );
+            token_2022::transfer_checked(transfer_ctx, leftover, decimals)?;
+        }
+        let swept = swept + leftover;
+
+        let close_ctx = CpiContext::new_with_signer(
+            ctx.accounts.mint_token_program.to_account_info(),
+            CloseAccount {
+                account: ctx.accounts.vault_token.to_account_info(),
+                destination: ctx.accounts.payer.to_account_info(),
+                authority: ctx.accounts.vault.to_account_info(),
+            },
+            signer_seeds,
+        );
+        token_2022::close_account(close_ctx)?;
+
+        // Nothing could ever sign for the mint again, no synth may be minted without the vault
+        let set_authority_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.clone(),
+            SetAuthority {
+                account_or_mint: ctx.accounts.synth_mint.to_account_info(),
+                current_authority: ctx.accounts.vault.to_account_info(),
+            },
+            signer_seeds,
+        );
+        token::set_authority(
+            set_authority_ctx,
+            spl_token::instruction::AuthorityType::MintTokens,
+            None,
+        )?;
+
+        // Closed last, so no CPI above runs with lamports moved under it
+        if *pool_info.owner == crate::ID {
+            let payer_info = ctx.accounts.payer.to_account_info();
+            **payer_info.try_borrow_mut_lamports()? += pool_info.lamports();
+            **pool_info.try_borrow_mut_lamports()? = 0;
+        }
+
+        emit!(CloseVaultEvent {
+            vault: ctx.accounts.vault.key(),
+            swept,
+            swept_reward,
+        });
+        Ok(())
+    }
+
+    pub fn migrate_vault(ctx: Context<MigrateVault>) -> ProgramResult {
+        let vault_info = ctx.accounts.vault.to_account_info();
+        let (old, version) = {
+            let data = vault_info.try_borrow_data()?;
+            if data.len() < 8 || data[..8] != Vault::discriminator() {
+                return Err(VaultError::InvalidAccountVersion.into());
+            }
+            // Version 0 accounts end before the version byte, later ones carry it
+            let version = if data.len() > Vault::VERSION_OFFSET {
+                data[Vault::VERSION_OFFSET]
+            } else {
+                0
+            };
+            // Every layout starts with the version 0 fields
+            (VaultV0::deserialize(&mut &data[8..])?, version)
         };
-        let cpi_program = ctx.accounts.token_program.clone();
-        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
-        token::transfer(cpi_ctx, amount)?;
+        if version >= Vault::VERSION {
+            msg!("Vault already at version {}", version);
+            return Err(VaultError::InvalidAccountVersion.into());
+        }
+        msg!(
+            "Migrate vault {} from version {} to {}",
+            vault_info.key,
+            version,
+            Vault::VERSION
+        );
 
-        let ref mut vault = ctx.accounts.vault;
-        vault.total_deposit += amount;
+        if old.payer != ctx.accounts.payer.key() {
+            return Err(VaultError::Unauthorized.into());
+        }
+        let vault_key = Pubkey::create_program_address(
+            &[
+                b"vault".as_ref(),
+                old.mint_token.as_ref(),
+                old.payer.as_ref(),
+                &[old.bump],
+            ],
+            ctx.program_id,
+        )?;
+        if vault_key != vault_info.key() {
+            return Err(VaultError::Unauthorized.into());
+        }
 
-        let ref mut treasure = ctx.accounts.treasure;
-        treasure.current_deposit += amount;
+        // Top up rent for the larger account before growing it
+        let lamports = ctx.accounts.rent.minimum_balance(Vault::SPACE);
+        if lamports > vault_info.lamports() {
+            invoke(
+                &solana_program::system_instruction::transfer(
+                    ctx.accounts.payer.key,
+                    vault_info.key,
+                    lamports - vault_info.lamports(),
+                ),
+                &[
+                    ctx.accounts.payer.clone(),
+                    vault_info.clone(),
+                    ctx.accounts.system_program.clone(),
+                ],
+            )?;
+        }
+        if vault_info.data_len() < Vault::SPACE {
+            vault_info.realloc(Vault::SPACE, true)?;
+        }
 
+        let mut data = vault_info.try_borrow_mut_data()?;
+        if version > 0 {
+            // Fields appended since read zero from the grown account, their default
+            data[Vault::VERSION_OFFSET] = Vault::VERSION;
+            return Ok(());
+        }
+        let vault = Vault {
+            bump: old.bump,
+            payer: old.payer,
+            mint_token: old.mint_token,
+            vault_token: old.vault_token,
+            synth_token: old.synth_token,
+            percent: old.percent,
+            total_deposit: old.total_deposit,
+            lending_program: ctx.accounts.lending_program.key(),
+            lending_market: ctx.accounts.lending_market.key(),
+            version: Vault::VERSION,
+            token_program: spl_token::ID,
+            ..Vault::default()
+        };
+        vault.try_serialize(&mut &mut data[..])?;
         Ok(())
     }
 
-    pub fn liquidate(ctx: Context<Liquidate>) -> ProgramResult {
+    pub fn migrate_treasure(
+        ctx: Context<MigrateTreasure>,
+        positions_bump: u8,
+        treasure_bump: u8,
+        index: u64,
+        old_bump: u8,
+    ) -> ProgramResult {
+        let old_info = ctx.accounts.old_treasure.to_account_info();
+        let old = {
+            let data = old_info.try_borrow_data()?;
+            if data.len() != 8 + size_of::<TreasureV0>() || data[..8] != Treasure::discriminator() {
+                return Err(VaultError::InvalidAccountVersion.into());
+            }
+            TreasureV0::deserialize(&mut &data[8..])?
+        };
+        msg!("Migrate treasure {} to index {}", old_info.key, index);
+
+        position::init_treasure(
+            &mut ctx.accounts.user_positions,
+            &mut ctx.accounts.treasure,
+            ctx.accounts.owner.key(),
+            ctx.accounts.vault.key(),
+            positions_bump,
+            treasure_bump,
+            index,
+        )?;
+        let treasure = &mut ctx.accounts.treasure;
+        treasure.current_deposit = old.current_deposit;
+        treasure.current_borrow = old.current_borrow;
+
+        // Close the old account to the owner
+        let owner_info = ctx.accounts.owner.to_account_info();
+        **owner_info.try_borrow_mut_lamports()? += old_info.lamports();
+        **old_info.try_borrow_mut_lamports()? = 0;
+        old_info.try_borrow_mut_data()?.fill(0);
+        Ok(())
+    }
+
+    /// A referrer account can follow the allow-list entry, if any, in the remaining accounts.
+    pub fn deposit(ctx: Context<Deposit>, amount: u64) -> ProgramResult {
+        msg!("Deposit {}", amount);
+        let referrer = position::verify_allowed(
+            &ctx.accounts.vault,
+            ctx.accounts.owner.key(),
+            ctx.remaining_accounts,
+        )?;
+        position::load_referrer(&ctx.accounts.vault, &mut ctx.accounts.treasure, referrer)?;
+        position::deposit_collateral(
+            &mut ctx.accounts.vault,
+            &mut ctx.accounts.treasure,
+            ctx.accounts.user_token.to_account_info(),
+            ctx.accounts.vault_token.to_account_info(),
+            ctx.accounts.mint_token.to_account_info(),
+            ctx.accounts.owner.clone(),
+            ctx.accounts.mint_token_program.to_account_info(),
+            amount,
+        )?;
+        Ok(())
+    }
+
+    pub fn deposit_for(ctx: Context<DepositFor>, amount: u64) -> ProgramResult {
+        msg!("Deposit {} for {}", amount, ctx.accounts.treasure.owner);
+        // The deposit is the beneficiary's, so is the allow-list entry
+        position::verify_allowed(
+            &ctx.accounts.vault,
+            ctx.accounts.treasure.owner,
+            ctx.remaining_accounts,
+        )?;
+        let amount = position::deposit_collateral(
+            &mut ctx.accounts.vault,
+            &mut ctx.accounts.treasure,
+            ctx.accounts.payer_token.to_account_info(),
+            ctx.accounts.vault_token.to_account_info(),
+            ctx.accounts.mint_token.to_account_info(),
+            ctx.accounts.payer.clone(),
+            ctx.accounts.mint_token_program.to_account_info(),
+            amount,
+        )?;
+
+        emit!(DepositForEvent {
+            vault: ctx.accounts.vault.key(),
+            treasure: ctx.accounts.treasure.key(),
+            payer: ctx.accounts.payer.key(),
+            beneficiary: ctx.accounts.treasure.owner,
+            amount,
+        });
+        Ok(())
+    }
+
+    /// Rewards the position earned are paid out with its deposit, to the reward vault and reward
+    /// account following the position NFT holder and mint, if any, in the remaining accounts.
+    pub fn liquidate<'info>(ctx: Context<'_, '_, '_, 'info, Liquidate<'info>>) -> ProgramResult {
         msg!("liquidate ");
-        let ref mut treasure = ctx.accounts.treasure;
+        let treasure = &mut ctx.accounts.treasure;
+        let position_token =
+            position::verify_authority(treasure, &ctx.accounts.owner, ctx.remaining_accounts)?;
+        let reward_accounts = match position_token {
+            Some(_) => ctx.remaining_accounts.get(2..).unwrap_or_default(),
+            None => ctx.remaining_accounts,
+        };
+        let user_token =
+            token_2022::check_account(&ctx.accounts.user_token, &ctx.accounts.vault.mint_token)?;
+        if user_token.owner != ctx.accounts.owner.key() {
+            return Err(VaultError::InvalidTokenAccount.into());
+        }
+
+        // The position is gone after this, so is its NFT
+        if let Some(position_token) = position_token {
+            let position_mint = ctx
+                .remaining_accounts
+                .get(1)
+                .ok_or(VaultError::Unauthorized)?;
+            let cpi_ctx = CpiContext::new(
+                ctx.accounts.token_program.clone(),
+                Burn {
+                    mint: position_mint.clone(),
+                    to: position_token,
+                    authority: ctx.accounts.owner.clone(),
+                },
+            );
+            token::burn(cpi_ctx, 1)?;
+        }
+
+        position::accrue_debt(
+            &mut ctx.accounts.vault,
+            treasure,
+            ctx.accounts.clock.unix_timestamp,
+        )?;
+        rewards::settle_rewards(
+            &mut ctx.accounts.vault,
+            treasure,
+            ctx.accounts.clock.unix_timestamp,
+        )?;
+        if treasure.reward_owed > 0 {
+            let (reward_vault, to) = match reward_accounts {
+                [reward_vault, to, ..] => (reward_vault.clone(), to.clone()),
+                _ => return Err(VaultError::UnclaimedRewards.into()),
+            };
+            rewards::pay_rewards(
+                &ctx.accounts.vault,
+                reward_vault,
+                to,
+                ctx.accounts.token_program.clone(),
+                treasure.reward_owed,
+            )?;
+            emit!(ClaimRewardsEvent {
+                vault: ctx.accounts.vault.key(),
+                treasure: treasure.key(),
+                amount: treasure.reward_owed,
+            });
+            treasure.reward_owed = 0;
+        }
 
         // Burn synth token
         let cpi_accounts = Burn {
@@ -136,10 +978,11 @@ pub mod magik {
         };
         let cpi_program = ctx.accounts.token_program.clone();
         let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
-        token::burn(cpi_ctx, treasure.current_borrow);
+        token::burn(cpi_ctx, treasure.current_borrow)?;
 
         // // Transfer back to user
-        let cpi_program = ctx.accounts.token_program.clone();
+        let cpi_program = ctx.accounts.mint_token_program.to_account_info();
+        let decimals = token_2022::unpack_mint(&ctx.accounts.mint_token)?.decimals;
         let seeds = &[
             b"vault".as_ref(),
             ctx.accounts.vault.mint_token.as_ref(),
@@ -149,31 +992,764 @@ pub mod magik {
         let signer_seeds = &[&seeds[..]];
         let transfer_ctx = CpiContext::new_with_signer(
             cpi_program,
-            Transfer {
+            token_2022::TransferChecked {
                 from: ctx.accounts.vault_token.to_account_info().clone(),
+                mint: ctx.accounts.mint_token.to_account_info(),
                 to: ctx.accounts.user_token.to_account_info().clone(),
                 authority: ctx.accounts.vault.to_account_info(),
             },
             signer_seeds,
         );
 
-        token::transfer(transfer_ctx, treasure.current_deposit)?;
+        token_2022::transfer_checked(transfer_ctx, treasure.current_deposit, decimals)?;
+
+        let vault = &mut ctx.accounts.vault;
+        vault.total_deposit -= treasure.current_deposit;
+        // Positions opened before vaults counted them may take it below zero
+        vault.open_positions = vault.open_positions.saturating_sub(1);
         Ok(())
     }
 
-    pub fn borrow(ctx: Context<Borrow>, bump: u8, amount: u64) -> ProgramResult {
+    /// A referrer account can follow the position NFT holder and allow-list entry, if any, in the
+    /// remaining accounts. Referred positions have to pass theirs.
+    pub fn borrow(ctx: Context<Borrow>, amount: u64) -> ProgramResult {
         msg!("Borrow {} ", amount);
-        let ref mut treasure = ctx.accounts.treasure;
-        let ref vault = ctx.accounts.vault;
-        msg!("Percent {} ", vault.percent);
-        let total_borrow = treasure.current_borrow + amount;
-        msg!("Current {} total {}", treasure.current_borrow, total_borrow);
-        if total_borrow / vault.percent * 100 > treasure.current_deposit {
-            return Err(VaultError::ExceedBorrowAmount.into());
+        let treasure = &mut ctx.accounts.treasure;
+        let position_token =
+            position::verify_authority(treasure, &ctx.accounts.owner, ctx.remaining_accounts)?;
+        let allow_list = match position_token {
+            Some(_) => &ctx.remaining_accounts[1..],
+            None => ctx.remaining_accounts,
+        };
+        let referrer =
+            position::verify_allowed(&ctx.accounts.vault, ctx.accounts.owner.key(), allow_list)?;
+        let mut referrer = position::load_referrer(&ctx.accounts.vault, treasure, referrer)?;
+        if referrer.is_none() && treasure.referrer != Pubkey::default() {
+            return Err(VaultError::InvalidReferrer.into());
         }
 
-        // User mint synthSTBL up to 50% of they STBL position
-        let cpi_program = ctx.accounts.token_program.clone();
+        position::borrow_synth(
+            &mut ctx.accounts.vault,
+            treasure,
+            ctx.accounts.synth_mint.to_account_info(),
+            ctx.accounts.user_synth.to_account_info(),
+            ctx.accounts.treasury.to_account_info(),
+            ctx.accounts.token_program.clone(),
+            &ctx.accounts.clock,
+            referrer.as_deref_mut(),
+            amount,
+        )?;
+        // Not one of the instruction's accounts, so not written back on its own
+        if let Some(referrer) = referrer {
+            referrer.exit(ctx.program_id)?;
+        }
+        Ok(())
+    }
+
+    pub fn repay(ctx: Context<Repay>, amount: u64) -> ProgramResult {
+        msg!("Repay {}", amount);
+        let treasure = &mut ctx.accounts.treasure;
+        position::verify_authority(treasure, &ctx.accounts.owner, ctx.remaining_accounts)?;
+        position::repay_synth(
+            &mut ctx.accounts.vault,
+            treasure,
+            ctx.accounts.synth_mint.to_account_info(),
+            ctx.accounts.user_synth.to_account_info(),
+            ctx.accounts.owner.clone(),
+            ctx.accounts.token_program.clone(),
+            &ctx.accounts.clock,
+            amount,
+        )?;
+        Ok(())
+    }
+
+    /// `deposit` followed by `borrow` on the same position, taking the same remaining accounts.
+    pub fn deposit_and_borrow(ctx: Context<Zap>, amount: u64, borrow_amount: u64) -> ProgramResult {
+        msg!("Deposit {} and borrow {}", amount, borrow_amount);
+        let treasure = &mut ctx.accounts.treasure;
+        let position_token =
+            position::verify_authority(treasure, &ctx.accounts.owner, ctx.remaining_accounts)?;
+        let allow_list = match position_token {
+            Some(_) => &ctx.remaining_accounts[1..],
+            None => ctx.remaining_accounts,
+        };
+        let referrer =
+            position::verify_allowed(&ctx.accounts.vault, ctx.accounts.owner.key(), allow_list)?;
+        let mut referrer = position::load_referrer(&ctx.accounts.vault, treasure, referrer)?;
+        if referrer.is_none() && treasure.referrer != Pubkey::default() {
+            return Err(VaultError::InvalidReferrer.into());
+        }
+
+        let deposited = position::deposit_collateral(
+            &mut ctx.accounts.vault,
+            treasure,
+            ctx.accounts.user_token.to_account_info(),
+            ctx.accounts.vault_token.to_account_info(),
+            ctx.accounts.mint_token.to_account_info(),
+            ctx.accounts.owner.clone(),
+            ctx.accounts.mint_token_program.to_account_info(),
+            amount,
+        )?;
+        position::borrow_synth(
+            &mut ctx.accounts.vault,
+            treasure,
+            ctx.accounts.synth_mint.to_account_info(),
+            ctx.accounts.user_synth.to_account_info(),
+            ctx.accounts.treasury.to_account_info(),
+            ctx.accounts.token_program.clone(),
+            &ctx.accounts.clock,
+            referrer.as_deref_mut(),
+            borrow_amount,
+        )?;
+        if let Some(referrer) = referrer {
+            referrer.exit(ctx.program_id)?;
+        }
+
+        emit!(DepositAndBorrowEvent {
+            vault: ctx.accounts.vault.key(),
+            treasure: treasure.key(),
+            deposited,
+            borrowed: borrow_amount,
+        });
+        Ok(())
+    }
+
+    /// `repay` followed by withdrawing `withdraw_amount` of the position's deposit.
+    pub fn repay_and_withdraw(
+        ctx: Context<Zap>,
+        amount: u64,
+        withdraw_amount: u64,
+    ) -> ProgramResult {
+        msg!("Repay {} and withdraw {}", amount, withdraw_amount);
+        let treasure = &mut ctx.accounts.treasure;
+        position::verify_authority(treasure, &ctx.accounts.owner, ctx.remaining_accounts)?;
+        let user_token =
+            token_2022::check_account(&ctx.accounts.user_token, &ctx.accounts.vault.mint_token)?;
+        if user_token.owner != ctx.accounts.owner.key() {
+            return Err(VaultError::InvalidTokenAccount.into());
+        }
+
+        let repaid = position::repay_synth(
+            &mut ctx.accounts.vault,
+            treasure,
+            ctx.accounts.synth_mint.to_account_info(),
+            ctx.accounts.user_synth.to_account_info(),
+            ctx.accounts.owner.clone(),
+            ctx.accounts.token_program.clone(),
+            &ctx.accounts.clock,
+            amount,
+        )?;
+        position::withdraw_collateral(
+            &mut ctx.accounts.vault,
+            treasure,
+            ctx.accounts.vault_token.to_account_info(),
+            ctx.accounts.mint_token.to_account_info(),
+            ctx.accounts.user_token.to_account_info(),
+            ctx.accounts.mint_token_program.to_account_info(),
+            withdraw_amount,
+        )?;
+
+        emit!(RepayAndWithdrawEvent {
+            vault: ctx.accounts.vault.key(),
+            treasure: treasure.key(),
+            repaid,
+            withdrawn: withdraw_amount,
+        });
+        Ok(())
+    }
+
+    /// Borrows synth, swaps it to mint_token through the vault's AMM and deposits the proceeds,
+    /// up to `loops` times while the position has room, until `total_borrow` is reached.
+    ///
+    /// AMM accounts follow the position NFT holder, allow-list entry and referrer, if any, in the
+    /// remaining accounts. Only referred positions pass their referrer.
+    pub fn leverage<'info>(
+        ctx: Context<'_, '_, '_, 'info, Leverage<'info>>,
+        total_borrow: u64,
+        loops: u8,
+        min_rate_bps: u64,
+    ) -> ProgramResult {
+        msg!("Leverage {} in {} loops", total_borrow, loops);
+        if loops == 0 || loops > Parameters::MAX_LEVERAGE_LOOPS {
+            return Err(VaultError::InvalidLeverage.into());
+        }
+        let treasure = &mut ctx.accounts.treasure;
+        let position_token =
+            position::verify_authority(treasure, &ctx.accounts.owner, ctx.remaining_accounts)?;
+        let allow_list = match position_token {
+            Some(_) => &ctx.remaining_accounts[1..],
+            None => ctx.remaining_accounts,
+        };
+        let referrer =
+            position::verify_allowed(&ctx.accounts.vault, ctx.accounts.owner.key(), allow_list)?;
+        let (mut referrer, amm_accounts) =
+            position::load_recorded_referrer(&ctx.accounts.vault, treasure, referrer)?;
+        let user_token =
+            token_2022::check_account(&ctx.accounts.user_token, &ctx.accounts.vault.mint_token)?;
+        if user_token.owner != ctx.accounts.owner.key() {
+            return Err(VaultError::InvalidTokenAccount.into());
+        }
+
+        let mut borrowed = 0;
+        let mut deposited = 0;
+        for _ in 0..loops {
+            let vault = &mut ctx.accounts.vault;
+            position::accrue_debt(vault, treasure, ctx.accounts.clock.unix_timestamp)?;
+            let headroom = Parameters::max_borrow(treasure.current_deposit, vault.percent)
+                .saturating_sub(treasure.current_borrow);
+            let amount =
+                Parameters::borrowable(headroom, vault.borrow_fee_bps).min(total_borrow - borrowed);
+            if amount == 0 {
+                break;
+            }
+
+            position::borrow_synth(
+                vault,
+                treasure,
+                ctx.accounts.synth_mint.to_account_info(),
+                ctx.accounts.user_synth.to_account_info(),
+                ctx.accounts.treasury.to_account_info(),
+                ctx.accounts.token_program.clone(),
+                &ctx.accounts.clock,
+                referrer.as_deref_mut(),
+                amount,
+            )?;
+
+            // The AMM isn't trusted with the slippage check, measure what arrived
+            let min_out = Parameters::min_swap_out(amount, min_rate_bps);
+            let before = token_2022::unpack_account(&ctx.accounts.user_token)?.amount;
+            amm::swap(
+                &ctx.accounts.amm_program.to_account_info(),
+                amm_accounts,
+                amount,
+                min_out,
+            )?;
+            let received = token_2022::unpack_account(&ctx.accounts.user_token)?
+                .amount
+                .saturating_sub(before);
+            if received < min_out {
+                return Err(VaultError::SlippageExceeded.into());
+            }
+
+            deposited += position::deposit_collateral(
+                vault,
+                treasure,
+                ctx.accounts.user_token.to_account_info(),
+                ctx.accounts.vault_token.to_account_info(),
+                ctx.accounts.mint_token.to_account_info(),
+                ctx.accounts.owner.clone(),
+                ctx.accounts.mint_token_program.to_account_info(),
+                received,
+            )?;
+            borrowed += amount;
+        }
+        if let Some(referrer) = referrer {
+            referrer.exit(ctx.program_id)?;
+        }
+
+        emit!(LeverageEvent {
+            vault: ctx.accounts.vault.key(),
+            treasure: treasure.key(),
+            borrowed,
+            deposited,
+        });
+        Ok(())
+    }
+
+    pub fn register_referrer(ctx: Context<RegisterReferrer>, bump: u8) -> ProgramResult {
+        msg!("Register referrer {}", ctx.accounts.owner.key());
+        let referrer = &mut ctx.accounts.referrer;
+        referrer.owner = ctx.accounts.owner.key();
+        referrer.vault = ctx.accounts.vault.key();
+        referrer.bump = bump;
+        Ok(())
+    }
+
+    /// Mints the borrow fees `referrer` earned to `referrer_synth`.
+    pub fn claim_referral_fees(ctx: Context<ClaimReferralFees>) -> ProgramResult {
+        let amount = ctx.accounts.referrer.accrued;
+        msg!("Claim {} referral fees", amount);
+        if amount > 0 {
+            let seeds = &[
+                b"vault".as_ref(),
+                ctx.accounts.vault.mint_token.as_ref(),
+                ctx.accounts.vault.payer.as_ref(),
+                &[ctx.accounts.vault.bump],
+            ];
+            let signer_seeds = &[&seeds[..]];
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.clone(),
+                MintTo {
+                    mint: ctx.accounts.synth_mint.to_account_info(),
+                    to: ctx.accounts.referrer_synth.to_account_info(),
+                    authority: ctx.accounts.vault.to_account_info(),
+                },
+                signer_seeds,
+            );
+            token::mint_to(cpi_ctx, amount)?;
+        }
+
+        ctx.accounts.referrer.accrued = 0;
+        let vault = &mut ctx.accounts.vault;
+        vault.referral_accrued = vault.referral_accrued.saturating_sub(amount);
+        emit!(ClaimReferralFeesEvent {
+            vault: ctx.accounts.vault.key(),
+            referrer: ctx.accounts.referrer.key(),
+            amount,
+        });
+        Ok(())
+    }
+
+    pub fn create_reward_vault(ctx: Context<CreateRewardVault>, bump: u8) -> ProgramResult {
+        msg!("Create reward vault {}", ctx.accounts.reward_vault.key());
+        let vault = &mut ctx.accounts.vault;
+        vault.reward_mint = ctx.accounts.reward_mint.key();
+        vault.reward_vault = ctx.accounts.reward_vault.key();
+        Ok(())
+    }
+
+    /// Adds `amount` to the rewards deposits earn and sets how fast they're emitted.
+    pub fn fund_rewards(ctx: Context<FundRewards>, amount: u64, reward_rate: u64) -> ProgramResult {
+        msg!("Fund rewards {} at {} per second", amount, reward_rate);
+        // Emissions up to now happen at the old rate
+        rewards::accrue_rewards(&mut ctx.accounts.vault, ctx.accounts.clock.unix_timestamp)?;
+
+        let cpi_ctx = CpiContext::new(
+            ctx.accounts.token_program.clone(),
+            Transfer {
+                from: ctx.accounts.payer_token.to_account_info(),
+                to: ctx.accounts.reward_vault.to_account_info(),
+                authority: ctx.accounts.payer.to_account_info(),
+            },
+        );
+        token::transfer(cpi_ctx, amount)?;
+
+        let vault = &mut ctx.accounts.vault;
+        vault.reward_remaining = vault
+            .reward_remaining
+            .checked_add(amount)
+            .ok_or(VaultError::MathOverflow)?;
+        vault.reward_rate = reward_rate;
+        emit!(FundRewardsEvent {
+            vault: vault.key(),
+            amount,
+            reward_rate,
+            reward_remaining: vault.reward_remaining,
+        });
+        Ok(())
+    }
+
+    pub fn claim_rewards(ctx: Context<ClaimRewards>) -> ProgramResult {
+        let treasure = &mut ctx.accounts.treasure;
+        position::verify_authority(treasure, &ctx.accounts.owner, ctx.remaining_accounts)?;
+        rewards::settle_rewards(
+            &mut ctx.accounts.vault,
+            treasure,
+            ctx.accounts.clock.unix_timestamp,
+        )?;
+        position::apply_losses(&ctx.accounts.vault, treasure);
+        let amount = treasure.reward_owed;
+        msg!("Claim {} rewards", amount);
+
+        if amount > 0 {
+            rewards::pay_rewards(
+                &ctx.accounts.vault,
+                ctx.accounts.reward_vault.to_account_info(),
+                ctx.accounts.user_reward.to_account_info(),
+                ctx.accounts.token_program.clone(),
+                amount,
+            )?;
+        }
+
+        treasure.reward_owed = 0;
+        emit!(ClaimRewardsEvent {
+            vault: ctx.accounts.vault.key(),
+            treasure: treasure.key(),
+            amount,
+        });
+        Ok(())
+    }
+
+    pub fn create_stability_pool(
+        ctx: Context<CreateStabilityPool>,
+        bump: u8,
+        synth_bump: u8,
+        collateral_bump: u8,
+    ) -> ProgramResult {
+        msg!("Create stability pool {}", ctx.accounts.pool.key());
+        let pool = &mut ctx.accounts.pool;
+        pool.vault = ctx.accounts.vault.key();
+        pool.bump = bump;
+        pool.pool_synth = ctx.accounts.pool_synth.key();
+        pool.pool_collateral = ctx.accounts.pool_collateral.key();
+        pool.product = Parameters::INDEX_PRECISION;
+        Ok(())
+    }
+
+    /// Adds `amount` synth to the owner's stability pool deposit, paying out collateral earned so far.
+    pub fn provide_to_pool(ctx: Context<ProvideToPool>, bump: u8, amount: u64) -> ProgramResult {
+        msg!("Provide {} to stability pool", amount);
+        // Flash minted synth could take a share of a liquidation it never backed
+        if ctx.accounts.vault.flash_mint != 0 {
+            return Err(VaultError::FlashLoanActive.into());
+        }
+        let deposit = &mut ctx.accounts.deposit;
+        if deposit.owner == Pubkey::default() {
+            deposit.owner = ctx.accounts.owner.key();
+            deposit.pool = ctx.accounts.pool.key();
+            deposit.bump = bump;
+        }
+        let remaining = stability::settle(
+            &ctx.accounts.vault,
+            &ctx.accounts.pool,
+            deposit,
+            &ctx.accounts.snapshot_scale,
+            &ctx.accounts.next_scale,
+            ctx.accounts.pool_collateral.to_account_info(),
+            ctx.accounts.mint_token.to_account_info(),
+            ctx.accounts.user_token.to_account_info(),
+            ctx.accounts.mint_token_program.to_account_info(),
+        )?;
+
+        let cpi_ctx = CpiContext::new(
+            ctx.accounts.token_program.clone(),
+            Transfer {
+                from: ctx.accounts.user_synth.to_account_info(),
+                to: ctx.accounts.pool_synth.to_account_info(),
+                authority: ctx.accounts.owner.clone(),
+            },
+        );
+        token::transfer(cpi_ctx, amount)?;
+
+        let pool = &mut ctx.accounts.pool;
+        stability::snapshot(pool, deposit, remaining + amount);
+        pool.total_deposits += amount;
+        Ok(())
+    }
+
+    /// Takes up to `amount` synth back out of the stability pool, paying out collateral earned so far.
+    pub fn withdraw_from_pool(ctx: Context<WithdrawFromPool>, amount: u64) -> ProgramResult {
+        msg!("Withdraw {} from stability pool", amount);
+        if ctx.accounts.vault.flash_mint != 0 {
+            return Err(VaultError::FlashLoanActive.into());
+        }
+        let deposit = &mut ctx.accounts.deposit;
+        let remaining = stability::settle(
+            &ctx.accounts.vault,
+            &ctx.accounts.pool,
+            deposit,
+            &ctx.accounts.snapshot_scale,
+            &ctx.accounts.next_scale,
+            ctx.accounts.pool_collateral.to_account_info(),
+            ctx.accounts.mint_token.to_account_info(),
+            ctx.accounts.user_token.to_account_info(),
+            ctx.accounts.mint_token_program.to_account_info(),
+        )?;
+        // Rounding can leave the last depositor a little short of their share
+        let amount = amount.min(remaining).min(ctx.accounts.pool_synth.amount);
+
+        let seeds = &[
+            b"vault".as_ref(),
+            ctx.accounts.vault.mint_token.as_ref(),
+            ctx.accounts.vault.payer.as_ref(),
+            &[ctx.accounts.vault.bump],
+        ];
+        let signer_seeds = &[&seeds[..]];
+        let cpi_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.clone(),
+            Transfer {
+                from: ctx.accounts.pool_synth.to_account_info(),
+                to: ctx.accounts.user_synth.to_account_info(),
+                authority: ctx.accounts.vault.to_account_info(),
+            },
+            signer_seeds,
+        );
+        token::transfer(cpi_ctx, amount)?;
+
+        let pool = &mut ctx.accounts.pool;
+        stability::snapshot(pool, deposit, remaining - amount);
+        pool.total_deposits = pool.total_deposits.saturating_sub(amount);
+        Ok(())
+    }
+
+    /// Cancels an unhealthy position's debt with stability pool synth, the debt's worth of its
+    /// collateral plus a bonus goes to the pool's depositors. The rest stays in the position,
+    /// left open and debt free for its owner. Anyone can call it.
+    pub fn liquidate_to_pool(ctx: Context<LiquidateToPool>) -> ProgramResult {
+        let treasure = &mut ctx.accounts.treasure;
+        position::accrue_debt(
+            &mut ctx.accounts.vault,
+            treasure,
+            ctx.accounts.clock.unix_timestamp,
+        )?;
+        let debt = treasure.current_borrow;
+        if debt <= Parameters::max_borrow(treasure.current_deposit, ctx.accounts.vault.percent) {
+            return Err(VaultError::NotLiquidatable.into());
+        }
+        let collateral = Parameters::seized_collateral(debt, treasure.current_deposit);
+        msg!("Liquidate {} debt for {} collateral", debt, collateral);
+
+        let pool = &mut ctx.accounts.pool;
+        let (epoch, scale) = (pool.epoch, pool.scale);
+        if let Some(sum) = stability::offset(pool, debt, collateral)? {
+            stability::record_scale(
+                &pool.key(),
+                epoch,
+                scale,
+                sum,
+                ctx.accounts.ended_scale.to_account_info(),
+                ctx.accounts.payer.to_account_info(),
+                ctx.accounts.system_program.clone(),
+                &ctx.accounts.rent,
+            )?;
+        }
+
+        let seeds = &[
+            b"vault".as_ref(),
+            ctx.accounts.vault.mint_token.as_ref(),
+            ctx.accounts.vault.payer.as_ref(),
+            &[ctx.accounts.vault.bump],
+        ];
+        let signer_seeds = &[&seeds[..]];
+        let burn_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.clone(),
+            Burn {
+                mint: ctx.accounts.synth_mint.to_account_info(),
+                to: ctx.accounts.pool_synth.to_account_info(),
+                authority: ctx.accounts.vault.to_account_info(),
+            },
+            signer_seeds,
+        );
+        token::burn(burn_ctx, debt)?;
+
+        let decimals = token_2022::unpack_mint(&ctx.accounts.mint_token)?.decimals;
+        let transfer_ctx = CpiContext::new_with_signer(
+            ctx.accounts.mint_token_program.to_account_info(),
+            token_2022::TransferChecked {
+                from: ctx.accounts.vault_token.to_account_info(),
+                mint: ctx.accounts.mint_token.to_account_info(),
+                to: ctx.accounts.pool_collateral.to_account_info(),
+                authority: ctx.accounts.vault.to_account_info(),
+            },
+            signer_seeds,
+        );
+        token_2022::transfer_checked(transfer_ctx, collateral, decimals)?;
+
+        treasure.current_borrow = 0;
+        treasure.current_deposit -= collateral;
+        let vault = &mut ctx.accounts.vault;
+        vault.total_deposit -= collateral;
+        emit!(LiquidateToPoolEvent {
+            vault: vault.key(),
+            treasure: treasure.key(),
+            debt,
+            collateral,
+        });
+        Ok(())
+    }
+
+    pub fn approve_delegate(
+        ctx: Context<ApproveDelegate>,
+        bump: u8,
+        borrow_limit: u64,
+        expires_at: i64,
+        can_repay: bool,
+    ) -> ProgramResult {
+        msg!("Approve delegate {}", ctx.accounts.delegate.key());
+        position::verify_authority(
+            &ctx.accounts.treasure,
+            &ctx.accounts.owner,
+            ctx.remaining_accounts,
+        )?;
+
+        let delegation = &mut ctx.accounts.delegation;
+        delegation.treasure = ctx.accounts.treasure.key();
+        delegation.delegate = ctx.accounts.delegate.key();
+        delegation.bump = bump;
+        delegation.borrow_limit = borrow_limit;
+        delegation.borrowed = 0;
+        delegation.expires_at = expires_at;
+        delegation.can_repay = can_repay;
+        delegation.approver = ctx.accounts.owner.key();
+        Ok(())
+    }
+
+    pub fn revoke_delegate(ctx: Context<RevokeDelegate>) -> ProgramResult {
+        msg!("Revoke delegate {}", ctx.accounts.delegation.delegate);
+        position::verify_authority(
+            &ctx.accounts.treasure,
+            &ctx.accounts.owner,
+            ctx.remaining_accounts,
+        )?;
+        Ok(())
+    }
+
+    /// Referred positions pass their referrer after the position NFT holder and allow-list entry,
+    /// if any, in the remaining accounts. A delegate can't refer a position.
+    pub fn delegated_borrow(ctx: Context<DelegatedBorrow>, amount: u64) -> ProgramResult {
+        msg!("Delegated borrow {}", amount);
+        let delegation = &mut ctx.accounts.delegation;
+        // A sold position NFT takes the approver's delegations with it
+        let position_token = position::verify_controller(
+            &ctx.accounts.treasure,
+            delegation.approver,
+            ctx.remaining_accounts,
+        )?;
+        let allow_list = match position_token {
+            Some(_) => &ctx.remaining_accounts[1..],
+            None => ctx.remaining_accounts,
+        };
+        let referrer =
+            position::verify_allowed(&ctx.accounts.vault, ctx.accounts.delegate.key(), allow_list)?;
+        let (mut referrer, _) = position::load_recorded_referrer(
+            &ctx.accounts.vault,
+            &mut ctx.accounts.treasure,
+            referrer,
+        )?;
+        if ctx.accounts.clock.unix_timestamp >= delegation.expires_at {
+            return Err(VaultError::DelegationExpired.into());
+        }
+        let borrowed = delegation
+            .borrowed
+            .checked_add(amount)
+            .ok_or(VaultError::MathOverflow)?;
+        if borrowed > delegation.borrow_limit {
+            return Err(VaultError::ExceedDelegateLimit.into());
+        }
+        delegation.borrowed = borrowed;
+
+        position::borrow_synth(
+            &mut ctx.accounts.vault,
+            &mut ctx.accounts.treasure,
+            ctx.accounts.synth_mint.to_account_info(),
+            ctx.accounts.receiver_synth.to_account_info(),
+            ctx.accounts.treasury.to_account_info(),
+            ctx.accounts.token_program.clone(),
+            &ctx.accounts.clock,
+            referrer.as_deref_mut(),
+            amount,
+        )?;
+        if let Some(referrer) = referrer {
+            referrer.exit(ctx.program_id)?;
+        }
+        Ok(())
+    }
+
+    pub fn delegated_repay(ctx: Context<DelegatedRepay>, amount: u64) -> ProgramResult {
+        msg!("Delegated repay {}", amount);
+        let delegation = &ctx.accounts.delegation;
+        if !delegation.can_repay {
+            return Err(VaultError::Unauthorized.into());
+        }
+        if ctx.accounts.clock.unix_timestamp >= delegation.expires_at {
+            return Err(VaultError::DelegationExpired.into());
+        }
+
+        position::repay_synth(
+            &mut ctx.accounts.vault,
+            &mut ctx.accounts.treasure,
+            ctx.accounts.synth_mint.to_account_info(),
+            ctx.accounts.delegate_synth.to_account_info(),
+            ctx.accounts.delegate.clone(),
+            ctx.accounts.token_program.clone(),
+            &ctx.accounts.clock,
+            amount,
+        )?;
+        Ok(())
+    }
+
+    /// Lends `amount` of idle vault_token, `flash_repay` must follow in the same transaction.
+    pub fn flash_borrow(ctx: Context<FlashBorrow>, amount: u64) -> ProgramResult {
+        msg!("Flash borrow {}", amount);
+        if ctx.accounts.vault.flash_loan != 0 {
+            return Err(VaultError::FlashLoanActive.into());
+        }
+        flash::require_repayment(
+            &ctx.accounts.instructions,
+            &ctx.accounts.vault.key(),
+            &instruction::FlashRepay {}.data(),
+        )?;
+
+        let seeds = &[
+            b"vault".as_ref(),
+            ctx.accounts.vault.mint_token.as_ref(),
+            ctx.accounts.vault.payer.as_ref(),
+            &[ctx.accounts.vault.bump],
+        ];
+        let signer_seeds = &[&seeds[..]];
+        let decimals = token_2022::unpack_mint(&ctx.accounts.mint_token)?.decimals;
+        let transfer_ctx = CpiContext::new_with_signer(
+            ctx.accounts.mint_token_program.to_account_info(),
+            token_2022::TransferChecked {
+                from: ctx.accounts.vault_token.to_account_info(),
+                mint: ctx.accounts.mint_token.to_account_info(),
+                to: ctx.accounts.receiver_token.to_account_info(),
+                authority: ctx.accounts.vault.to_account_info(),
+            },
+            signer_seeds,
+        );
+        token_2022::transfer_checked(transfer_ctx, amount, decimals)?;
+
+        ctx.accounts.vault.flash_loan = amount;
+        Ok(())
+    }
+
+    /// Pays back the outstanding flash loan plus its fee, which grows every deposit pro-rata.
+    pub fn flash_repay(ctx: Context<FlashRepay>) -> ProgramResult {
+        let amount = ctx.accounts.vault.flash_loan;
+        if amount == 0 {
+            return Err(VaultError::InvalidFlashLoan.into());
+        }
+        let fee = Parameters::flash_loan_fee(amount);
+        let owed = amount.checked_add(fee).ok_or(VaultError::MathOverflow)?;
+        msg!("Flash repay {} fee {}", amount, fee);
+
+        let decimals = token_2022::unpack_mint(&ctx.accounts.mint_token)?.decimals;
+        let before = token_2022::unpack_account(&ctx.accounts.vault_token)?.amount;
+        let transfer_ctx = CpiContext::new(
+            ctx.accounts.mint_token_program.to_account_info(),
+            token_2022::TransferChecked {
+                from: ctx.accounts.repayer_token.to_account_info(),
+                mint: ctx.accounts.mint_token.to_account_info(),
+                to: ctx.accounts.vault_token.to_account_info(),
+                authority: ctx.accounts.repayer.clone(),
+            },
+        );
+        token_2022::transfer_checked(transfer_ctx, owed, decimals)?;
+
+        // Transfer fees on the mint are the borrower's to cover
+        let received = token_2022::unpack_account(&ctx.accounts.vault_token)?
+            .amount
+            .saturating_sub(before);
+        if received < owed {
+            return Err(VaultError::FlashLoanNotRepaid.into());
+        }
+
+        let vault = &mut ctx.accounts.vault;
+        vault.flash_loan = 0;
+        // Emissions up to now are shared by the deposits as they were
+        rewards::accrue_rewards(vault, Clock::get()?.unix_timestamp)?;
+        valuation::record_gain(vault, fee)?;
+        emit!(FlashLoanEvent {
+            vault: vault.key(),
+            amount,
+            fee,
+        });
+        Ok(())
+    }
+
+    /// Mints `amount` synth to any account, `flash_burn` must follow in the same transaction.
+    pub fn flash_mint(ctx: Context<FlashMint>, amount: u64) -> ProgramResult {
+        msg!("Flash mint {}", amount);
+        if ctx.accounts.vault.flash_mint != 0 {
+            return Err(VaultError::FlashLoanActive.into());
+        }
+        flash::require_repayment(
+            &ctx.accounts.instructions,
+            &ctx.accounts.vault.key(),
+            &instruction::FlashBurn {}.data(),
+        )?;
+
         let seeds = &[
             b"vault".as_ref(),
             ctx.accounts.vault.mint_token.as_ref(),
@@ -182,24 +1758,142 @@ pub mod magik {
         ];
         let signer_seeds = &[&seeds[..]];
         let mint_to_ctx = CpiContext::new_with_signer(
-            cpi_program,
+            ctx.accounts.token_program.clone(),
             MintTo {
-                mint: ctx.accounts.synth_mint.to_account_info().clone(),
-                to: ctx.accounts.user_synth.to_account_info().clone(),
-                authority: ctx.accounts.vault.to_account_info().clone(),
+                mint: ctx.accounts.synth_mint.to_account_info(),
+                to: ctx.accounts.receiver_synth.to_account_info(),
+                authority: ctx.accounts.vault.to_account_info(),
             },
             signer_seeds,
         );
-
         token::mint_to(mint_to_ctx, amount)?;
 
-        treasure.current_borrow += amount;
+        ctx.accounts.vault.flash_mint = amount;
+        Ok(())
+    }
+
+    /// Burns the outstanding flash mint, its fee goes to the treasury or is burned when there is none.
+    pub fn flash_burn(ctx: Context<FlashBurn>) -> ProgramResult {
+        let amount = ctx.accounts.vault.flash_mint;
+        if amount == 0 {
+            return Err(VaultError::InvalidFlashLoan.into());
+        }
+        let fee = Parameters::flash_loan_fee(amount);
+        msg!("Flash burn {} fee {}", amount, fee);
 
+        let to_treasury = ctx.accounts.vault.treasury != Pubkey::default();
+        let burned = if to_treasury {
+            amount
+        } else {
+            amount.checked_add(fee).ok_or(VaultError::MathOverflow)?
+        };
+        let burn_ctx = CpiContext::new(
+            ctx.accounts.token_program.clone(),
+            Burn {
+                mint: ctx.accounts.synth_mint.to_account_info(),
+                to: ctx.accounts.payer_synth.to_account_info(),
+                authority: ctx.accounts.payer.clone(),
+            },
+        );
+        token::burn(burn_ctx, burned)?;
+
+        if to_treasury && fee > 0 {
+            if ctx.accounts.treasury.key() != ctx.accounts.vault.treasury {
+                return Err(VaultError::InvalidTreasury.into());
+            }
+            let transfer_ctx = CpiContext::new(
+                ctx.accounts.token_program.clone(),
+                Transfer {
+                    from: ctx.accounts.payer_synth.to_account_info(),
+                    to: ctx.accounts.treasury.to_account_info(),
+                    authority: ctx.accounts.payer.clone(),
+                },
+            );
+            token::transfer(transfer_ctx, fee)?;
+        }
+
+        ctx.accounts.vault.flash_mint = 0;
+        emit!(FlashMintEvent {
+            vault: ctx.accounts.vault.key(),
+            amount,
+            fee,
+        });
+        Ok(())
+    }
+
+    pub fn get_position(ctx: Context<GetPosition>) -> ProgramResult {
+        let treasure = &ctx.accounts.treasure;
+        let vault = &ctx.accounts.vault;
+        let valuation = valuation::value_vault(
+            vault,
+            &ctx.accounts.vault_token.to_account_info(),
+            &ctx.accounts.collateral.to_account_info(),
+            &ctx.accounts.reserve.to_account_info(),
+            &ctx.accounts.clock,
+        )?;
+
+        let view = position::view(
+            vault,
+            treasure,
+            &valuation,
+            ctx.accounts.clock.unix_timestamp,
+        )?;
+        msg!("Position {:?}", view);
+
+        set_return_data(&view.try_to_vec()?);
+        Ok(())
+    }
+
+    /// Records the shortfall when the Port reserve lost value, cutting every deposit pro-rata.
+    pub fn record_loss(ctx: Context<RecordLoss>) -> ProgramResult {
+        // Flash loaned liquidity is out of vault_token but still owed to it
+        if ctx.accounts.vault.flash_loan != 0 {
+            return Err(VaultError::FlashLoanActive.into());
+        }
+        // An unpinned vault never lent, valuing it could only count lent liquidity as lost
+        if ctx.accounts.vault.collateral == Pubkey::default() {
+            return Err(VaultError::InvalidCollateral.into());
+        }
+        let valuation = valuation::value_vault(
+            &ctx.accounts.vault,
+            &ctx.accounts.vault_token.to_account_info(),
+            &ctx.accounts.collateral.to_account_info(),
+            &ctx.accounts.reserve.to_account_info(),
+            &ctx.accounts.clock,
+        )?;
+
+        let vault = &mut ctx.accounts.vault;
+        // Emissions up to now are shared by the deposits as they were
+        rewards::accrue_rewards(vault, ctx.accounts.clock.unix_timestamp)?;
+        let loss = valuation::record_loss(vault, &valuation)?;
+        msg!("Loss {} total deposit {}", loss, vault.total_deposit);
+        if loss > 0 {
+            emit!(LossRecordedEvent {
+                vault: vault.key(),
+                loss,
+                total_deposit: vault.total_deposit,
+                deposit_index: vault.deposit_index,
+            });
+        }
         Ok(())
     }
 
     pub fn lending_crank(ctx: Context<LendingCrank>, lending_amount: u64) -> ProgramResult {
-        let ref mut vault = ctx.accounts.vault;
+        let reserve = valuation::load_reserve(
+            &ctx.accounts.vault,
+            &ctx.accounts.reserve.to_account_info(),
+            &ctx.accounts.clock,
+        )?;
+        let destination = ctx.accounts.destination_collateral.to_account_info();
+        let vault = &mut ctx.accounts.vault;
+        // Valuation only counts the pinned account, collateral minted anywhere else would read as lost
+        if vault.collateral == Pubkey::default() {
+            valuation::load_collateral(vault.key(), &destination, &reserve)?;
+            vault.collateral = destination.key();
+            msg!("Pin collateral {}", vault.collateral);
+        } else if destination.key() != vault.collateral {
+            return Err(VaultError::InvalidCollateral.into());
+        }
 
         let port_program = ctx.accounts.port_program.to_account_info();
         let seeds = &[
@@ -233,7 +1927,7 @@ pub mod magik {
     }
 
     pub fn redeem_crank(ctx: Context<RedeemCrank>, redeem_amount: u64) -> ProgramResult {
-        let ref mut vault = ctx.accounts.vault;
+        let vault = &mut ctx.accounts.vault;
 
         let port_program = ctx.accounts.port_program.to_account_info();
         let seeds = &[
@@ -266,3 +1960,42 @@ pub mod magik {
         Ok(())
     }
 }
+
+/// Moves `amount` out of an SPL token account owned by the vault being closed.
+fn sweep_token_account<'info>(
+    accounts: &CloseVault<'info>,
+    from: AccountInfo<'info>,
+    to: AccountInfo<'info>,
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    token::transfer(
+        CpiContext::new_with_signer(
+            accounts.token_program.clone(),
+            Transfer {
+                from,
+                to,
+                authority: accounts.vault.to_account_info(),
+            },
+            signer_seeds,
+        ),
+        amount,
+    )
+}
+
+/// Closes an empty SPL token account owned by the vault being closed, rent goes to the payer.
+fn close_token_account<'info>(
+    accounts: &CloseVault<'info>,
+    account: AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    token::close_account(CpiContext::new_with_signer(
+        accounts.token_program.clone(),
+        CloseAccount {
+            account,
+            destination: accounts.payer.to_account_info(),
+            authority: accounts.vault.to_account_info(),
+        },
+        signer_seeds,
+    ))
+}
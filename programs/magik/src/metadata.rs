@@ -0,0 +1,192 @@
+use anchor_lang::prelude::*;
+use solana_program::instruction::Instruction;
+use solana_program::program::invoke_signed;
+
+use crate::VaultError;
+
+pub mod metadata_program {
+    solana_program::declare_id!("metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s");
+}
+
+/// Instruction index of `CreateMetadataAccountV3` in the token metadata program
+pub const CREATE_METADATA_ACCOUNT_V3: u8 = 33;
+/// Instruction index of `UpdateMetadataAccountV2` in the token metadata program
+pub const UPDATE_METADATA_ACCOUNT_V2: u8 = 15;
+
+/// Longest name, symbol and uri the token metadata program stores
+pub const MAX_NAME_LENGTH: usize = 32;
+pub const MAX_SYMBOL_LENGTH: usize = 10;
+pub const MAX_URI_LENGTH: usize = 200;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct Creator {
+    pub address: Pubkey,
+    pub verified: bool,
+    pub share: u8,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct Collection {
+    pub verified: bool,
+    pub key: Pubkey,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub enum UseMethod {
+    Burn,
+    Multiple,
+    Single,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct Uses {
+    pub use_method: UseMethod,
+    pub remaining: u64,
+    pub total: u64,
+}
+
+/// Mirrors Metaplex `DataV2`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct DataV2 {
+    pub name: String,
+    pub symbol: String,
+    pub uri: String,
+    pub seller_fee_basis_points: u16,
+    pub creators: Option<Vec<Creator>>,
+    pub collection: Option<Collection>,
+    pub uses: Option<Uses>,
+}
+
+impl DataV2 {
+    pub fn fungible(name: String, symbol: String, uri: String) -> Self {
+        DataV2 {
+            name,
+            symbol,
+            uri,
+            seller_fee_basis_points: 0,
+            creators: None,
+            collection: None,
+            uses: None,
+        }
+    }
+
+    /// Metadata of a synth mint, once `name`, `symbol` and `uri` are checked.
+    ///
+    /// Synth symbols are `m` and the underlying's symbol, like mUSDC, so wallets tell them apart
+    /// from the token they are minted against.
+    pub fn synth(name: String, symbol: String, uri: String) -> Result<Self, ProgramError> {
+        let underlying = symbol.strip_prefix('m').unwrap_or_default();
+        let valid_symbol = !underlying.is_empty()
+            && symbol.len() <= MAX_SYMBOL_LENGTH
+            && underlying
+                .bytes()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit());
+        let valid_uri = uri.is_empty() || uri.starts_with("https://");
+        if name.trim().is_empty()
+            || name.len() > MAX_NAME_LENGTH
+            || !valid_symbol
+            || !valid_uri
+            || uri.len() > MAX_URI_LENGTH
+        {
+            return Err(VaultError::InvalidSynthMetadata.into());
+        }
+        Ok(DataV2::fungible(name, symbol, uri))
+    }
+}
+
+pub fn find_metadata_address(mint: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[
+            b"metadata".as_ref(),
+            metadata_program::ID.as_ref(),
+            mint.as_ref(),
+        ],
+        &metadata_program::ID,
+    )
+}
+
+pub fn create_metadata<'a, 'b, 'c, 'info>(
+    ctx: CpiContext<'a, 'b, 'c, 'info, CreateMetadata<'info>>,
+    data: DataV2,
+) -> ProgramResult {
+    let mut ix_data = vec![CREATE_METADATA_ACCOUNT_V3];
+    data.serialize(&mut ix_data)?;
+    // is_mutable, collection_details
+    true.serialize(&mut ix_data)?;
+    ix_data.push(0);
+
+    let ix = Instruction {
+        program_id: ctx.program.key(),
+        accounts: vec![
+            AccountMeta::new(ctx.accounts.metadata.key(), false),
+            AccountMeta::new_readonly(ctx.accounts.mint.key(), false),
+            AccountMeta::new_readonly(ctx.accounts.mint_authority.key(), true),
+            AccountMeta::new(ctx.accounts.payer.key(), true),
+            AccountMeta::new_readonly(ctx.accounts.update_authority.key(), true),
+            AccountMeta::new_readonly(ctx.accounts.system_program.key(), false),
+            AccountMeta::new_readonly(ctx.accounts.rent.key(), false),
+        ],
+        data: ix_data,
+    };
+
+    invoke_signed(
+        &ix,
+        &[
+            ctx.accounts.metadata,
+            ctx.accounts.mint,
+            ctx.accounts.mint_authority,
+            ctx.accounts.payer,
+            ctx.accounts.update_authority,
+            ctx.accounts.system_program,
+            ctx.accounts.rent,
+            ctx.program,
+        ],
+        ctx.signer_seeds,
+    )
+}
+
+#[derive(Accounts)]
+pub struct CreateMetadata<'info> {
+    pub metadata: AccountInfo<'info>,
+    pub mint: AccountInfo<'info>,
+    pub mint_authority: AccountInfo<'info>,
+    pub payer: AccountInfo<'info>,
+    pub update_authority: AccountInfo<'info>,
+    pub system_program: AccountInfo<'info>,
+    pub rent: AccountInfo<'info>,
+}
+
+pub fn update_metadata<'a, 'b, 'c, 'info>(
+    ctx: CpiContext<'a, 'b, 'c, 'info, UpdateMetadata<'info>>,
+    data: DataV2,
+) -> ProgramResult {
+    let mut ix_data = vec![UPDATE_METADATA_ACCOUNT_V2];
+    // data, new update authority, primary sale happened, is_mutable
+    Some(data).serialize(&mut ix_data)?;
+    ix_data.extend_from_slice(&[0, 0, 0]);
+
+    let ix = Instruction {
+        program_id: ctx.program.key(),
+        accounts: vec![
+            AccountMeta::new(ctx.accounts.metadata.key(), false),
+            AccountMeta::new_readonly(ctx.accounts.update_authority.key(), true),
+        ],
+        data: ix_data,
+    };
+
+    invoke_signed(
+        &ix,
+        &[
+            ctx.accounts.metadata,
+            ctx.accounts.update_authority,
+            ctx.program,
+        ],
+        ctx.signer_seeds,
+    )
+}
+
+#[derive(Accounts)]
+pub struct UpdateMetadata<'info> {
+    pub metadata: AccountInfo<'info>,
+    pub update_authority: AccountInfo<'info>,
+}
@@ -2,8 +2,133 @@ pub struct Parameters {}
 
 impl Parameters {
     pub const MAX_PERCENT: u64 = 50;
+    pub const HEALTH_PRECISION: u64 = 10_000;
+    pub const BPS_PRECISION: u64 = 10_000;
+    pub const MAX_BORROW_FEE_BPS: u64 = 1_000;
+    // Borrow index starts at 1.0 with 18 decimals
+    pub const INDEX_PRECISION: u128 = 1_000_000_000_000_000_000;
+    // About 100% a year, as a per second rate scaled by INDEX_PRECISION
+    pub const MAX_INTEREST_RATE: u64 = 31_709_791_983;
+    pub const FLASH_LOAN_FEE_BPS: u64 = 9;
+    // Each loop is a borrow, a swap and a deposit, keep within the compute budget
+    pub const MAX_LEVERAGE_LOOPS: u8 = 4;
+    // Longest notice a vault may give before queued changes take effect
+    pub const MAX_TIMELOCK_DELAY: i64 = 30 * 24 * 60 * 60;
+    // Longest mint limit window, keeps the window arithmetic far from overflowing
+    pub const MAX_MINT_WINDOW: i64 = 30 * 24 * 60 * 60;
+    // Part of the borrow fee paid to the position's referrer
+    pub const REFERRAL_SHARE_BPS: u64 = 2_000;
+    // Collateral over the debt a stability pool liquidation seizes, for the pool's depositors
+    pub const LIQUIDATION_BONUS_BPS: u64 = 1_000;
 
     pub fn verify_percent(percent: u64) {
-        assert_eq!(percent <= Parameters::MAX_PERCENT, true);
+        assert!(percent <= Parameters::MAX_PERCENT);
+    }
+
+    pub fn verify_mint_window(mint_window: i64) {
+        assert!(mint_window > 0);
+        assert!(mint_window <= Parameters::MAX_MINT_WINDOW);
+    }
+
+    pub fn verify_timelock_delay(timelock_delay: i64) {
+        assert!(timelock_delay >= 0);
+        assert!(timelock_delay <= Parameters::MAX_TIMELOCK_DELAY);
+    }
+
+    pub fn verify_fees(borrow_fee_bps: u64, interest_rate: u64) {
+        assert!(borrow_fee_bps <= Parameters::MAX_BORROW_FEE_BPS);
+        assert!(interest_rate <= Parameters::MAX_INTEREST_RATE);
+    }
+
+    /// Origination fee charged on borrowing `amount`.
+    pub fn borrow_fee(amount: u64, borrow_fee_bps: u64) -> u64 {
+        (amount as u128 * borrow_fee_bps as u128 / Parameters::BPS_PRECISION as u128) as u64
+    }
+
+    /// Referrer's part of a `fee` charged on a referred position.
+    pub fn referral_fee(fee: u64) -> u64 {
+        (fee as u128 * Parameters::REFERRAL_SHARE_BPS as u128 / Parameters::BPS_PRECISION as u128)
+            as u64
+    }
+
+    /// Largest borrow whose amount plus borrow fee fits in `headroom`.
+    pub fn borrowable(headroom: u64, borrow_fee_bps: u64) -> u64 {
+        let bps = Parameters::BPS_PRECISION as u128;
+        (headroom as u128 * bps / (bps + borrow_fee_bps as u128)) as u64
+    }
+
+    /// Least mint_token a swap of `amount` synth may return, `min_rate_bps` of a 1:1 peg.
+    pub fn min_swap_out(amount: u64, min_rate_bps: u64) -> u64 {
+        (amount as u128 * min_rate_bps as u128 / Parameters::BPS_PRECISION as u128) as u64
+    }
+
+    /// Fee owed on a flash loan of `amount`, rounded up so small loans aren't free.
+    // Rounded by hand, u128::div_ceil needs Rust 1.73 and the BPF toolchain is older
+    #[allow(clippy::manual_div_ceil)]
+    pub fn flash_loan_fee(amount: u64) -> u64 {
+        let bps = Parameters::BPS_PRECISION as u128;
+        ((amount as u128 * Parameters::FLASH_LOAN_FEE_BPS as u128 + bps - 1) / bps) as u64
+    }
+
+    /// Grows `index` by simple interest at `rate` per second over `elapsed` seconds.
+    pub fn accrue_index(index: u128, rate: u64, elapsed: u64) -> Option<u128> {
+        let growth = (rate as u128).checked_mul(elapsed as u128)?;
+        index.checked_add(index.checked_mul(growth)? / Parameters::INDEX_PRECISION)
+    }
+
+    /// Debt of `borrow` taken at `from_index`, once the index reached `to_index`.
+    pub fn scale_debt(borrow: u64, from_index: u128, to_index: u128) -> Option<u64> {
+        let debt = (borrow as u128).checked_mul(to_index)? / from_index;
+        if debt > u64::MAX as u128 {
+            return None;
+        }
+        Some(debt as u64)
+    }
+
+    /// Reward per unit deposited at a deposit index of 1.0 when `emitted` is spread over
+    /// `total_deposit` standing at `deposit_index`, scaled by `INDEX_PRECISION`.
+    ///
+    /// Counting in units from before any loss keeps a position's share when losses cut it.
+    pub fn reward_per_share(emitted: u64, total_deposit: u64, deposit_index: u128) -> u128 {
+        emitted as u128 * deposit_index / total_deposit as u128
+    }
+
+    /// Rewards `deposit`, standing at `deposit_index`, earned while the reward per share grew by
+    /// `per_share`.
+    pub fn reward_earned(deposit: u64, deposit_index: u128, per_share: u128) -> Option<u64> {
+        let whole = (deposit as u128).checked_mul(per_share / deposit_index)?;
+        let part = (deposit as u128).checked_mul(per_share % deposit_index)? / deposit_index;
+        let earned = whole.checked_add(part)?;
+        if earned > u64::MAX as u128 {
+            return None;
+        }
+        Some(earned as u64)
+    }
+
+    /// What is left of `deposit` made at `from_index` once the deposit index fell to `to_index`.
+    pub fn cut_deposit(deposit: u64, from_index: u128, to_index: u128) -> u64 {
+        ((deposit as u128).saturating_mul(to_index) / from_index) as u64
+    }
+
+    /// Most synth a position holding `deposit` may have outstanding.
+    pub fn max_borrow(deposit: u64, percent: u64) -> u64 {
+        (deposit as u128 * percent as u128 / 100) as u64
+    }
+
+    /// Collateral a stability pool liquidation of `debt` seizes out of a position's `deposit`.
+    pub fn seized_collateral(debt: u64, deposit: u64) -> u64 {
+        let bonus = Parameters::BPS_PRECISION + Parameters::LIQUIDATION_BONUS_BPS;
+        let seized = debt as u128 * bonus as u128 / Parameters::BPS_PRECISION as u128;
+        seized.min(deposit as u128) as u64
+    }
+
+    /// Borrow capacity over outstanding debt, scaled by `HEALTH_PRECISION`.
+    /// Anything below `HEALTH_PRECISION` is over the vault's limit.
+    pub fn health_ratio(max_borrow: u64, debt: u64) -> u64 {
+        if debt == 0 {
+            return u64::MAX;
+        }
+        let ratio = max_borrow as u128 * Parameters::HEALTH_PRECISION as u128 / debt as u128;
+        ratio.min(u64::MAX as u128) as u64
     }
 }
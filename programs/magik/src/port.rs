@@ -86,6 +86,66 @@ pub struct PortDeposit<'info> {
 pub enum VaultError {
     #[msg("Exceed Borrow Amount")]
     ExceedBorrowAmount,
+    #[msg("Reserve does not back this vault")]
+    InvalidReserve,
+    #[msg("Reserve must be refreshed first")]
+    StaleReserve,
+    #[msg("Collateral account does not belong to this vault")]
+    InvalidCollateral,
+    #[msg("Math overflow")]
+    MathOverflow,
+    #[msg("Position index must be the owner's next position")]
+    InvalidPositionIndex,
+    #[msg("Signer does not control this position")]
+    Unauthorized,
+    #[msg("Position is tokenized, transfer its NFT instead")]
+    TokenizedPosition,
+    #[msg("Delegation has expired")]
+    DelegationExpired,
+    #[msg("Exceed delegated borrow limit")]
+    ExceedDelegateLimit,
+    #[msg("Account is not at a version this instruction can migrate")]
+    InvalidAccountVersion,
+    #[msg("Metadata account is not the synth mint's metadata PDA")]
+    InvalidMetadata,
+    #[msg("Token account does not match the vault's mint or owner")]
+    InvalidTokenAccount,
+    #[msg("Treasury does not match the vault's treasury")]
+    InvalidTreasury,
+    #[msg("A flash loan is already outstanding")]
+    FlashLoanActive,
+    #[msg("Flash loan is not repaid in the same transaction")]
+    FlashLoanNotRepaid,
+    #[msg("Flash loans must be taken directly by the transaction")]
+    InvalidFlashLoan,
+    #[msg("Withdraw exceeds the position's deposit")]
+    InsufficientDeposit,
+    #[msg("Leverage loops out of range")]
+    InvalidLeverage,
+    #[msg("Swap returned less than the slippage limit")]
+    SlippageExceeded,
+    #[msg("Position is within its borrow limit")]
+    NotLiquidatable,
+    #[msg("Stability pool can't absorb this debt")]
+    InsufficientStabilityPool,
+    #[msg("Vault minted its synth limit for this window")]
+    MintLimitExceeded,
+    #[msg("Vault changes are timelocked, queue them instead")]
+    TimelockActive,
+    #[msg("Queued update isn't executable yet")]
+    TimelockNotExpired,
+    #[msg("Wallet isn't on the vault's allow-list")]
+    NotAllowListed,
+    #[msg("Pass the reward accounts to pay out the position's rewards")]
+    UnclaimedRewards,
+    #[msg("Invalid referrer")]
+    InvalidReferrer,
+    #[msg("Vault still holds funds in Port, rewards or its stability pool")]
+    VaultNotEmpty,
+    #[msg("Scale account isn't the stability pool's for that epoch and scale")]
+    InvalidStabilityScale,
+    #[msg("Synth name, symbol or uri is empty, too long or malformed")]
+    InvalidSynthMetadata,
 }
 
 pub fn init_obligation<'a, 'b, 'c, 'info>(
@@ -0,0 +1,424 @@
+use anchor_lang::accounts::program_account::ProgramAccount;
+use anchor_lang::prelude::*;
+use anchor_spl::token::{self, Burn, MintTo, TokenAccount};
+
+use crate::parameters::Parameters;
+use crate::rewards;
+use crate::state::{AllowListEntry, ReferralEvent, Referrer, Treasure, UserPositions, Vault};
+use crate::token_2022::{self, TransferChecked};
+use crate::valuation::Valuation;
+use crate::{PositionView, VaultError};
+
+/// Hands out the owner's next position index and sets up the new `treasure` under it.
+pub fn init_treasure(
+    user_positions: &mut UserPositions,
+    treasure: &mut Treasure,
+    owner: Pubkey,
+    vault: Pubkey,
+    positions_bump: u8,
+    treasure_bump: u8,
+    index: u64,
+) -> ProgramResult {
+    if user_positions.owner == Pubkey::default() {
+        user_positions.owner = owner;
+        user_positions.vault = vault;
+        user_positions.bump = positions_bump;
+    }
+    // Indexes are handed out in order and never reused, even once a position is closed
+    if index != user_positions.count {
+        return Err(VaultError::InvalidPositionIndex.into());
+    }
+    user_positions.count += 1;
+
+    treasure.owner = owner;
+    treasure.vault = vault;
+    treasure.bump = treasure_bump;
+    treasure.index = index;
+    treasure.version = Treasure::VERSION;
+    Ok(())
+}
+
+/// Checks that `signer` controls `treasure`.
+///
+/// Plain positions are controlled by their owner. Once tokenized, whoever holds the position NFT
+/// does, and must pass their NFT token account as the first remaining account.
+pub fn verify_authority<'info>(
+    treasure: &Treasure,
+    signer: &AccountInfo,
+    remaining_accounts: &[AccountInfo<'info>],
+) -> std::result::Result<Option<AccountInfo<'info>>, ProgramError> {
+    if !signer.is_signer {
+        return Err(VaultError::Unauthorized.into());
+    }
+    verify_controller(treasure, signer.key(), remaining_accounts)
+}
+
+/// Checks that `controller` still controls `treasure`, the same way as `verify_authority`
+/// without requiring its signature.
+pub fn verify_controller<'info>(
+    treasure: &Treasure,
+    controller: Pubkey,
+    remaining_accounts: &[AccountInfo<'info>],
+) -> std::result::Result<Option<AccountInfo<'info>>, ProgramError> {
+    if treasure.position_mint == Pubkey::default() {
+        if treasure.owner != controller {
+            return Err(VaultError::Unauthorized.into());
+        }
+        return Ok(None);
+    }
+
+    let holder_info = remaining_accounts.first().ok_or(VaultError::Unauthorized)?;
+    if *holder_info.owner != spl_token::ID {
+        return Err(VaultError::Unauthorized.into());
+    }
+    let holder = TokenAccount::try_deserialize(&mut &holder_info.data.borrow()[..])?;
+    if holder.mint != treasure.position_mint || holder.owner != controller || holder.amount != 1 {
+        return Err(VaultError::Unauthorized.into());
+    }
+    Ok(Some(holder_info.clone()))
+}
+
+/// Checks that `wallet` may deposit and borrow in `vault`, returns the accounts after its entry.
+///
+/// Permissioned vaults need the wallet's allow-list entry as the first of `remaining_accounts`,
+/// open vaults take nothing.
+pub fn verify_allowed<'a, 'info>(
+    vault: &ProgramAccount<Vault>,
+    wallet: Pubkey,
+    remaining_accounts: &'a [AccountInfo<'info>],
+) -> std::result::Result<&'a [AccountInfo<'info>], ProgramError> {
+    if !vault.permissioned {
+        return Ok(remaining_accounts);
+    }
+    let entry_info = remaining_accounts
+        .first()
+        .ok_or(VaultError::NotAllowListed)?;
+    if *entry_info.owner != crate::ID {
+        return Err(VaultError::NotAllowListed.into());
+    }
+    let entry = AllowListEntry::try_deserialize(&mut &entry_info.data.borrow()[..])?;
+    if entry.vault != vault.key() || entry.wallet != wallet {
+        return Err(VaultError::NotAllowListed.into());
+    }
+    Ok(&remaining_accounts[1..])
+}
+
+/// Loads the referrer account passed as the first of `remaining_accounts`, if any.
+///
+/// A position records the first referrer it's used with and only accepts that one afterwards.
+/// Owners can't refer themselves.
+pub fn load_referrer<'info>(
+    vault: &ProgramAccount<Vault>,
+    treasure: &mut ProgramAccount<Treasure>,
+    remaining_accounts: &[AccountInfo<'info>],
+) -> std::result::Result<Option<Account<'info, Referrer>>, ProgramError> {
+    let referrer_info = match remaining_accounts.first() {
+        Some(referrer_info) => referrer_info,
+        None => return Ok(None),
+    };
+    let referrer = Account::<Referrer>::try_from(referrer_info)?;
+    if referrer.vault != vault.key() {
+        return Err(VaultError::InvalidReferrer.into());
+    }
+
+    if treasure.referrer == Pubkey::default() {
+        if referrer.owner == treasure.owner {
+            return Err(VaultError::InvalidReferrer.into());
+        }
+        treasure.referrer = referrer.key();
+        emit!(ReferralEvent {
+            vault: vault.key(),
+            treasure: treasure.key(),
+            referrer: referrer.key(),
+        });
+    } else if treasure.referrer != referrer.key() {
+        return Err(VaultError::InvalidReferrer.into());
+    }
+    Ok(Some(referrer))
+}
+
+/// Loads the referrer a referred `treasure` has to pass as the first of `remaining_accounts`,
+/// returns it with the accounts after it. Unreferred positions take nothing and stay unreferred.
+pub fn load_recorded_referrer<'a, 'info>(
+    vault: &ProgramAccount<Vault>,
+    treasure: &mut ProgramAccount<Treasure>,
+    remaining_accounts: &'a [AccountInfo<'info>],
+) -> std::result::Result<(Option<Account<'info, Referrer>>, &'a [AccountInfo<'info>]), ProgramError>
+{
+    if treasure.referrer == Pubkey::default() {
+        return Ok((None, remaining_accounts));
+    }
+    let referrer =
+        load_referrer(vault, treasure, remaining_accounts)?.ok_or(VaultError::InvalidReferrer)?;
+    Ok((Some(referrer), &remaining_accounts[1..]))
+}
+
+/// Brings `treasure`'s debt up to the vault's borrow index as of `now`, and its deposit down to
+/// the losses recorded since it was last touched, once it was credited its rewards.
+pub fn accrue_debt(vault: &mut Vault, treasure: &mut Treasure, now: i64) -> ProgramResult {
+    rewards::settle_rewards(vault, treasure, now)?;
+    apply_losses(vault, treasure);
+    vault.accrue_interest(now)?;
+    treasure.current_borrow = debt_at(treasure, vault.borrow_index)?;
+    treasure.borrow_index = vault.borrow_index;
+    Ok(())
+}
+
+/// Cuts `treasure`'s deposit by the vault's losses recorded since it was last touched.
+///
+/// Settle its rewards first, they were earned by the deposit before the cut.
+pub fn apply_losses(vault: &Vault, treasure: &mut Treasure) {
+    let index = vault.current_deposit_index();
+    treasure.current_deposit = deposit_at(treasure, index);
+    treasure.deposit_index = index;
+}
+
+/// What is left of `treasure`'s deposit once the vault's deposit index reaches `index`.
+pub fn deposit_at(treasure: &Treasure, index: u128) -> u64 {
+    // Positions untouched since before the first loss were made at 1.0
+    let from_index = if treasure.deposit_index == 0 {
+        Parameters::INDEX_PRECISION
+    } else {
+        treasure.deposit_index
+    };
+    if from_index == index {
+        return treasure.current_deposit;
+    }
+    Parameters::cut_deposit(treasure.current_deposit, from_index, index)
+}
+
+/// What `treasure` owes once the vault's borrow index reaches `index`.
+pub fn debt_at(treasure: &Treasure, index: u128) -> std::result::Result<u64, ProgramError> {
+    // Debt taken before interest existed starts accruing from its first interaction
+    if treasure.borrow_index == 0 || treasure.current_borrow == 0 {
+        return Ok(treasure.current_borrow);
+    }
+    Parameters::scale_debt(treasure.current_borrow, treasure.borrow_index, index)
+        .ok_or_else(|| VaultError::MathOverflow.into())
+}
+
+/// What `get_position` reports for `treasure`, given the vault's `valuation` as of `now`.
+pub fn view(
+    vault: &Vault,
+    treasure: &Treasure,
+    valuation: &Valuation,
+    now: i64,
+) -> std::result::Result<PositionView, ProgramError> {
+    let deposit = deposit_at(treasure, vault.current_deposit_index());
+    let accrued_repayment = valuation.accrued_yield(vault.total_deposit, deposit)?;
+    let limit = Parameters::max_borrow(deposit, vault.percent);
+    let debt = debt_at(treasure, vault.current_borrow_index(now)?)?;
+    Ok(PositionView {
+        deposit_value: deposit + accrued_repayment,
+        debt,
+        max_borrow: Parameters::borrowable(limit.saturating_sub(debt), vault.borrow_fee_bps),
+        health_ratio: Parameters::health_ratio(limit, debt),
+        accrued_repayment,
+    })
+}
+
+/// Mints `amount` synth against `treasure` into `to`, within the vault's borrow limit.
+///
+/// The vault's borrow fee is minted to `treasury` and added to the debt. Given the position's
+/// `referrer`, its share of the fee accrues there instead.
+#[allow(clippy::too_many_arguments)]
+pub fn borrow_synth<'info>(
+    vault: &mut ProgramAccount<'info, Vault>,
+    treasure: &mut Treasure,
+    synth_mint: AccountInfo<'info>,
+    to: AccountInfo<'info>,
+    treasury: AccountInfo<'info>,
+    token_program: AccountInfo<'info>,
+    clock: &Clock,
+    referrer: Option<&mut Referrer>,
+    amount: u64,
+) -> ProgramResult {
+    accrue_debt(vault, treasure, clock.unix_timestamp)?;
+
+    msg!("Percent {} ", vault.percent);
+    let fee = Parameters::borrow_fee(amount, vault.borrow_fee_bps);
+    let total_borrow = treasure
+        .current_borrow
+        .checked_add(amount)
+        .and_then(|total| total.checked_add(fee))
+        .ok_or(VaultError::MathOverflow)?;
+    msg!("Current {} total {}", treasure.current_borrow, total_borrow);
+    if total_borrow > Parameters::max_borrow(treasure.current_deposit, vault.percent) {
+        return Err(VaultError::ExceedBorrowAmount.into());
+    }
+    vault.record_mint(amount + fee, clock.unix_timestamp)?;
+
+    // User mint synthSTBL up to 50% of they STBL position
+    let seeds = &[
+        b"vault".as_ref(),
+        vault.mint_token.as_ref(),
+        vault.payer.as_ref(),
+        &[vault.bump],
+    ];
+    let signer_seeds = &[&seeds[..]];
+    let mint_to_ctx = CpiContext::new_with_signer(
+        token_program.clone(),
+        MintTo {
+            mint: synth_mint.clone(),
+            to,
+            authority: vault.to_account_info(),
+        },
+        signer_seeds,
+    );
+    token::mint_to(mint_to_ctx, amount)?;
+
+    // Minted when the referrer claims, the mint limit already counted it
+    let referral_fee = match referrer {
+        Some(referrer) => {
+            let referral_fee = Parameters::referral_fee(fee);
+            referrer.accrued = referrer
+                .accrued
+                .checked_add(referral_fee)
+                .ok_or(VaultError::MathOverflow)?;
+            referral_fee
+        }
+        None => 0,
+    };
+    let fee = fee - referral_fee;
+    if fee > 0 {
+        if treasury.key() != vault.treasury {
+            return Err(VaultError::InvalidTreasury.into());
+        }
+        let fee_ctx = CpiContext::new_with_signer(
+            token_program,
+            MintTo {
+                mint: synth_mint,
+                to: treasury,
+                authority: vault.to_account_info(),
+            },
+            signer_seeds,
+        );
+        token::mint_to(fee_ctx, fee)?;
+    }
+
+    vault.referral_accrued = vault
+        .referral_accrued
+        .checked_add(referral_fee)
+        .ok_or(VaultError::MathOverflow)?;
+    treasure.current_borrow = total_borrow;
+    Ok(())
+}
+
+/// Burns synth from `from` to pay down `treasure`'s debt, returns the amount repaid.
+#[allow(clippy::too_many_arguments)]
+pub fn repay_synth<'info>(
+    vault: &mut Vault,
+    treasure: &mut Treasure,
+    synth_mint: AccountInfo<'info>,
+    from: AccountInfo<'info>,
+    authority: AccountInfo<'info>,
+    token_program: AccountInfo<'info>,
+    clock: &Clock,
+    amount: u64,
+) -> std::result::Result<u64, ProgramError> {
+    accrue_debt(vault, treasure, clock.unix_timestamp)?;
+
+    let amount = amount.min(treasure.current_borrow);
+    let cpi_ctx = CpiContext::new(
+        token_program,
+        Burn {
+            mint: synth_mint,
+            to: from,
+            authority,
+        },
+    );
+    token::burn(cpi_ctx, amount)?;
+
+    treasure.current_borrow -= amount;
+    Ok(amount)
+}
+
+/// Moves `amount` of `mint_token` from `from` into the vault and credits it to `treasure`.
+///
+/// Only what reaches vault_token is credited, Token-2022 transfer fees are the depositor's.
+/// Returns the credited amount.
+#[allow(clippy::too_many_arguments)]
+pub fn deposit_collateral<'info>(
+    vault: &mut ProgramAccount<'info, Vault>,
+    treasure: &mut Treasure,
+    from: AccountInfo<'info>,
+    vault_token: AccountInfo<'info>,
+    mint_token: AccountInfo<'info>,
+    authority: AccountInfo<'info>,
+    token_program: AccountInfo<'info>,
+    amount: u64,
+) -> std::result::Result<u64, ProgramError> {
+    if token_2022::check_account(&from, &vault.mint_token)?.owner != authority.key() {
+        return Err(VaultError::InvalidTokenAccount.into());
+    }
+    rewards::settle_rewards(vault, treasure, Clock::get()?.unix_timestamp)?;
+    apply_losses(vault, treasure);
+    let decimals = token_2022::unpack_mint(&mint_token)?.decimals;
+    let before = token_2022::unpack_account(&vault_token)?.amount;
+
+    let cpi_ctx = CpiContext::new(
+        token_program,
+        TransferChecked {
+            from,
+            mint: mint_token,
+            to: vault_token.clone(),
+            authority,
+        },
+    );
+    token_2022::transfer_checked(cpi_ctx, amount, decimals)?;
+
+    let received = token_2022::unpack_account(&vault_token)?
+        .amount
+        .checked_sub(before)
+        .ok_or(VaultError::MathOverflow)?;
+    vault.total_deposit += received;
+    treasure.current_deposit += received;
+    Ok(received)
+}
+
+/// Sends `amount` of the position's deposit from the vault to `to`.
+///
+/// What stays deposited must still cover `treasure`'s debt, accrue it first.
+pub fn withdraw_collateral<'info>(
+    vault: &mut ProgramAccount<'info, Vault>,
+    treasure: &mut Treasure,
+    vault_token: AccountInfo<'info>,
+    mint_token: AccountInfo<'info>,
+    to: AccountInfo<'info>,
+    token_program: AccountInfo<'info>,
+    amount: u64,
+) -> ProgramResult {
+    rewards::settle_rewards(vault, treasure, Clock::get()?.unix_timestamp)?;
+    apply_losses(vault, treasure);
+    let remaining = treasure
+        .current_deposit
+        .checked_sub(amount)
+        .ok_or(VaultError::InsufficientDeposit)?;
+    if treasure.current_borrow > Parameters::max_borrow(remaining, vault.percent) {
+        return Err(VaultError::ExceedBorrowAmount.into());
+    }
+
+    let seeds = &[
+        b"vault".as_ref(),
+        vault.mint_token.as_ref(),
+        vault.payer.as_ref(),
+        &[vault.bump],
+    ];
+    let signer_seeds = &[&seeds[..]];
+    let decimals = token_2022::unpack_mint(&mint_token)?.decimals;
+    let cpi_ctx = CpiContext::new_with_signer(
+        token_program,
+        TransferChecked {
+            from: vault_token,
+            mint: mint_token,
+            to,
+            authority: vault.to_account_info(),
+        },
+        signer_seeds,
+    );
+    token_2022::transfer_checked(cpi_ctx, amount, decimals)?;
+
+    vault.total_deposit -= amount;
+    treasure.current_deposit = remaining;
+    Ok(())
+}
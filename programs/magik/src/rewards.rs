@@ -0,0 +1,95 @@
+use anchor_lang::accounts::program_account::ProgramAccount;
+use anchor_lang::prelude::*;
+use anchor_spl::token::{self, TokenAccount, Transfer};
+
+use crate::parameters::Parameters;
+use crate::state::{Treasure, Vault};
+use crate::VaultError;
+
+/// Spreads what the vault emitted since the last update over its deposits.
+pub fn accrue_rewards(vault: &mut Vault, now: i64) -> ProgramResult {
+    let elapsed = now.saturating_sub(vault.reward_last_update).max(0) as u64;
+    vault.reward_last_update = now;
+    // Nobody is deposited to earn it, the emission stays in the reward vault
+    if vault.total_deposit == 0 {
+        return Ok(());
+    }
+    let emitted = vault
+        .reward_rate
+        .saturating_mul(elapsed)
+        .min(vault.reward_remaining);
+    if emitted == 0 {
+        return Ok(());
+    }
+    vault.reward_per_share = vault
+        .reward_per_share
+        .checked_add(Parameters::reward_per_share(
+            emitted,
+            vault.total_deposit,
+            vault.current_deposit_index(),
+        ))
+        .ok_or(VaultError::MathOverflow)?;
+    vault.reward_remaining -= emitted;
+    Ok(())
+}
+
+/// Credits `treasure` with the rewards its deposit earned up to `now`.
+///
+/// Call before the deposit changes, losses included, it earns at its old size until then.
+pub fn settle_rewards(vault: &mut Vault, treasure: &mut Treasure, now: i64) -> ProgramResult {
+    accrue_rewards(vault, now)?;
+    // Positions untouched since before the first loss were made at 1.0
+    let deposit_index = if treasure.deposit_index == 0 {
+        Parameters::INDEX_PRECISION
+    } else {
+        treasure.deposit_index
+    };
+    let earned = Parameters::reward_earned(
+        treasure.current_deposit,
+        deposit_index,
+        vault.reward_per_share - treasure.reward_per_share,
+    )
+    .ok_or(VaultError::MathOverflow)?;
+    treasure.reward_owed = treasure
+        .reward_owed
+        .checked_add(earned)
+        .ok_or(VaultError::MathOverflow)?;
+    treasure.reward_per_share = vault.reward_per_share;
+    Ok(())
+}
+
+/// Sends `amount` of the vault's rewards from `reward_vault` to `to`, any account of the reward
+/// mint.
+pub fn pay_rewards<'info>(
+    vault: &ProgramAccount<'info, Vault>,
+    reward_vault: AccountInfo<'info>,
+    to: AccountInfo<'info>,
+    token_program: AccountInfo<'info>,
+    amount: u64,
+) -> ProgramResult {
+    if reward_vault.key() != vault.reward_vault {
+        return Err(VaultError::InvalidTokenAccount.into());
+    }
+    let to_account: Account<TokenAccount> = Account::try_from(&to)?;
+    if to_account.mint != vault.reward_mint {
+        return Err(VaultError::InvalidTokenAccount.into());
+    }
+
+    let seeds = &[
+        b"vault".as_ref(),
+        vault.mint_token.as_ref(),
+        vault.payer.as_ref(),
+        &[vault.bump],
+    ];
+    let signer_seeds = &[&seeds[..]];
+    let cpi_ctx = CpiContext::new_with_signer(
+        token_program,
+        Transfer {
+            from: reward_vault,
+            to,
+            authority: vault.to_account_info(),
+        },
+        signer_seeds,
+    );
+    token::transfer(cpi_ctx, amount)
+}
@@ -0,0 +1,253 @@
+use anchor_lang::accounts::program_account::ProgramAccount;
+use anchor_lang::prelude::*;
+use solana_program::program::{invoke, invoke_signed};
+use solana_program::system_instruction;
+use std::mem::size_of;
+
+use crate::parameters::Parameters;
+use crate::state::{StabilityDeposit, StabilityPool, StabilityScale, Vault};
+use crate::token_2022::{self, TransferChecked};
+use crate::VaultError;
+
+// Once the product falls below this it is scaled back up by it, starting a new scale
+pub const SCALE_FACTOR: u128 = 1_000_000_000;
+
+/// `a * b / c` without overflowing when `b / c` is small but `a * b` isn't.
+fn mul_div(a: u64, b: u128, c: u128) -> Option<u64> {
+    let whole = (a as u128).checked_mul(b / c)?;
+    let part = (a as u128).checked_mul(b % c)? / c;
+    let result = whole.checked_add(part)?;
+    if result > u64::MAX as u128 {
+        return None;
+    }
+    Some(result as u64)
+}
+
+/// PDA keeping the sum `pool` ended `epoch` and `scale` at.
+pub fn scale_address(pool: &Pubkey, epoch: u64, scale: u64) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[
+            b"pool_scale",
+            pool.as_ref(),
+            epoch.to_le_bytes().as_ref(),
+            scale.to_le_bytes().as_ref(),
+        ],
+        &crate::ID,
+    )
+}
+
+/// Collateral sum `pool` reached in `epoch` and `scale`, `scale_info` being its scale PDA.
+pub fn scale_sum(
+    pool_key: &Pubkey,
+    pool: &StabilityPool,
+    scale_info: &AccountInfo,
+    epoch: u64,
+    scale: u64,
+) -> std::result::Result<u128, ProgramError> {
+    if scale_info.key() != scale_address(pool_key, epoch, scale).0 {
+        return Err(VaultError::InvalidStabilityScale.into());
+    }
+    if epoch == pool.epoch && scale == pool.scale {
+        return Ok(pool.sum);
+    }
+    // Scales the pool never reached earned nothing
+    if *scale_info.owner != crate::ID {
+        return Ok(0);
+    }
+    let scale = StabilityScale::try_deserialize(&mut &scale_info.try_borrow_data()?[..])?;
+    Ok(scale.sum)
+}
+
+/// What is left of `deposit` after the liquidations the pool absorbed since it was made.
+///
+/// Deposits from an earlier epoch were used up, so were those more than a scale behind.
+pub fn compensated_deposit(
+    pool: &StabilityPool,
+    deposit: &StabilityDeposit,
+) -> std::result::Result<u64, ProgramError> {
+    if deposit.amount == 0 || deposit.snapshot_epoch != pool.epoch {
+        return Ok(0);
+    }
+    let left = mul_div(deposit.amount, pool.product, deposit.snapshot_product)
+        .ok_or(VaultError::MathOverflow)?;
+    Ok(match pool.scale - deposit.snapshot_scale {
+        0 => left,
+        1 => left / SCALE_FACTOR as u64,
+        _ => 0,
+    })
+}
+
+/// Collateral `deposit` earned from the liquidations the pool absorbed since it was made.
+///
+/// `sum` and `next_sum` are the pool's sums for the deposit's snapshot scale and the one after,
+/// a deposit shrinks too much for any later scale to count.
+pub fn collateral_gain(
+    deposit: &StabilityDeposit,
+    sum: u128,
+    next_sum: u128,
+) -> std::result::Result<u64, ProgramError> {
+    if deposit.amount == 0 {
+        return Ok(0);
+    }
+    mul_div(
+        deposit.amount,
+        sum - deposit.snapshot_sum + next_sum / SCALE_FACTOR,
+        deposit.snapshot_product,
+    )
+    .ok_or_else(|| VaultError::MathOverflow.into())
+}
+
+/// Cancels `debt` against the pool's synth and hands `collateral` to its depositors, returns
+/// the sum the pool's scale ended at if this moved it to a new one.
+///
+/// Each unit deposited shrinks by `debt / total` and earns `collateral / total`, tracked as a
+/// running product and sum so depositors settle on their own later. A product too small to
+/// stay precise is scaled up into a new scale, a pool emptied starts a new epoch.
+pub fn offset(
+    pool: &mut StabilityPool,
+    debt: u64,
+    collateral: u64,
+) -> std::result::Result<Option<u128>, ProgramError> {
+    if debt == 0 {
+        return Ok(None);
+    }
+    if debt > pool.total_deposits {
+        return Err(VaultError::InsufficientStabilityPool.into());
+    }
+    let total = pool.total_deposits as u128;
+    pool.sum = (collateral as u128)
+        .checked_mul(pool.product)
+        .map(|gain| gain / total)
+        .and_then(|gain| pool.sum.checked_add(gain))
+        .ok_or(VaultError::MathOverflow)?;
+    pool.total_deposits -= debt;
+
+    let left = pool.product * (total - debt as u128);
+    let product = left / total;
+    if product >= SCALE_FACTOR {
+        pool.product = product;
+        return Ok(None);
+    }
+
+    let ended = pool.sum;
+    let scaled = product * SCALE_FACTOR + left % total * SCALE_FACTOR / total;
+    // Nothing left worth tracking, whatever dust remains belongs to no one
+    if pool.total_deposits == 0 || scaled == 0 {
+        pool.epoch += 1;
+        pool.scale = 0;
+        pool.product = Parameters::INDEX_PRECISION;
+        pool.total_deposits = 0;
+    } else {
+        pool.scale += 1;
+        pool.product = scaled;
+    }
+    pool.sum = 0;
+    Ok(Some(ended))
+}
+
+/// Creates `scale_info`, the PDA of `pool`'s `epoch` and `scale`, keeping the `sum` it ended at.
+#[allow(clippy::too_many_arguments)]
+pub fn record_scale<'info>(
+    pool_key: &Pubkey,
+    epoch: u64,
+    scale: u64,
+    sum: u128,
+    scale_info: AccountInfo<'info>,
+    payer: AccountInfo<'info>,
+    system_program: AccountInfo<'info>,
+    rent: &Rent,
+) -> ProgramResult {
+    let (key, bump) = scale_address(pool_key, epoch, scale);
+    if scale_info.key() != key {
+        return Err(VaultError::InvalidStabilityScale.into());
+    }
+    let epoch = epoch.to_le_bytes();
+    let scale = scale.to_le_bytes();
+    let seeds = &[
+        b"pool_scale".as_ref(),
+        pool_key.as_ref(),
+        epoch.as_ref(),
+        scale.as_ref(),
+        &[bump],
+    ];
+    let signer_seeds = &[&seeds[..]];
+
+    // Lamports sent to the address beforehand would make create_account fail, top them up instead
+    let space = size_of::<StabilityScale>() + 8;
+    let lamports = rent
+        .minimum_balance(space)
+        .saturating_sub(scale_info.lamports());
+    if lamports > 0 {
+        invoke(
+            &system_instruction::transfer(payer.key, &key, lamports),
+            &[payer, scale_info.clone(), system_program.clone()],
+        )?;
+    }
+    invoke_signed(
+        &system_instruction::allocate(&key, space as u64),
+        &[scale_info.clone(), system_program.clone()],
+        signer_seeds,
+    )?;
+    invoke_signed(
+        &system_instruction::assign(&key, &crate::ID),
+        &[scale_info.clone(), system_program],
+        signer_seeds,
+    )?;
+
+    let mut data = scale_info.try_borrow_mut_data()?;
+    StabilityScale { sum }.try_serialize(&mut &mut data[..])
+}
+
+/// Pays `deposit`'s collateral gain out to `to`, returns what is left of the deposit.
+///
+/// `snapshot_scale` and `next_scale` are the pool's scale PDAs for the deposit's snapshot epoch
+/// and scale, and the scale after. Follow with `snapshot` once the deposit's new amount is known.
+#[allow(clippy::too_many_arguments)]
+pub fn settle<'info>(
+    vault: &ProgramAccount<'info, Vault>,
+    pool: &ProgramAccount<'info, StabilityPool>,
+    deposit: &StabilityDeposit,
+    snapshot_scale: &AccountInfo<'info>,
+    next_scale: &AccountInfo<'info>,
+    pool_collateral: AccountInfo<'info>,
+    mint_token: AccountInfo<'info>,
+    to: AccountInfo<'info>,
+    token_program: AccountInfo<'info>,
+) -> std::result::Result<u64, ProgramError> {
+    let epoch = deposit.snapshot_epoch;
+    let scale = deposit.snapshot_scale;
+    let sum = scale_sum(&pool.key(), pool, snapshot_scale, epoch, scale)?;
+    let next_sum = scale_sum(&pool.key(), pool, next_scale, epoch, scale + 1)?;
+    let gain = collateral_gain(deposit, sum, next_sum)?;
+    if gain > 0 {
+        let seeds = &[
+            b"vault".as_ref(),
+            vault.mint_token.as_ref(),
+            vault.payer.as_ref(),
+            &[vault.bump],
+        ];
+        let signer_seeds = &[&seeds[..]];
+        let decimals = token_2022::unpack_mint(&mint_token)?.decimals;
+        let cpi_ctx = CpiContext::new_with_signer(
+            token_program,
+            TransferChecked {
+                from: pool_collateral,
+                mint: mint_token,
+                to,
+                authority: vault.to_account_info(),
+            },
+            signer_seeds,
+        );
+        token_2022::transfer_checked(cpi_ctx, gain, decimals)?;
+    }
+    compensated_deposit(pool, deposit)
+}
+
+/// Restarts `deposit` at `amount` from the pool's current state.
+pub fn snapshot(pool: &StabilityPool, deposit: &mut StabilityDeposit, amount: u64) {
+    deposit.amount = amount;
+    deposit.snapshot_product = pool.product;
+    deposit.snapshot_sum = pool.sum;
+    deposit.snapshot_scale = pool.scale;
+    deposit.snapshot_epoch = pool.epoch;
+}
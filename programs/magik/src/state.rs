@@ -1,18 +1,22 @@
 #![allow(unused)]
 use anchor_lang::accounts::program_account::ProgramAccount;
 use anchor_lang::prelude::*;
-use anchor_lang::solana_program::{pubkey::Pubkey, system_program};
+use anchor_lang::solana_program::{pubkey::Pubkey, system_program, sysvar};
 use anchor_spl::token::{self, Mint, TokenAccount};
 use std::mem::size_of;
+
+use crate::parameters::Parameters;
+use crate::VaultError;
 #[derive(Accounts)]
 pub struct RedeemCrank<'info> {
     pub vault: ProgramAccount<'info, Vault>,
+    #[account(address = vault.lending_program)]
     pub port_program: UncheckedAccount<'info>,
 
-    #[account(mut)]
+    #[account(mut, address = vault.collateral)]
     pub source_collateral: AccountInfo<'info>,
 
-    #[account(mut)]
+    #[account(mut, address = vault.vault_token)]
     pub destination_liquidity: AccountInfo<'info>,
     #[account(mut)]
     pub reserve: AccountInfo<'info>,
@@ -40,7 +44,7 @@ pub struct RedeemCrank<'info> {
 
 #[derive(Accounts)]
 pub struct UpdateVault<'info> {
-    #[account(mut)]
+    #[account(mut, has_one = payer)]
     pub vault: ProgramAccount<'info, Vault>,
 
     #[account(mut, constraint = vault.payer == payer.key() )]
@@ -48,91 +52,72 @@ pub struct UpdateVault<'info> {
 }
 
 #[derive(Accounts)]
-pub struct LendingCrank<'info> {
+pub struct UpdateFees<'info> {
+    #[account(mut, has_one = payer)]
     pub vault: ProgramAccount<'info, Vault>,
-    pub port_program: UncheckedAccount<'info>,
-    #[account(mut)]
-    pub source_liquidity: UncheckedAccount<'info>,
 
-    #[account(mut, constraint = vault.payer == payer.key() )]
+    #[account(constraint = treasury.mint == vault.synth_token)]
+    pub treasury: Account<'info, TokenAccount>,
+
     pub payer: Signer<'info>,
+    pub clock: Sysvar<'info, Clock>,
+}
 
-    #[account(mut)]
-    pub destination_collateral: UncheckedAccount<'info>,
-    #[account(mut)]
-    pub reserve: UncheckedAccount<'info>,
-    #[account(mut)]
-    pub reserve_liquidity_supply: UncheckedAccount<'info>,
-    #[account(mut)]
-    pub reserve_collateral_mint: UncheckedAccount<'info>,
-    #[account(mut)]
-    pub lending_market: UncheckedAccount<'info>,
-    #[account(mut)]
-    pub lending_market_authority: UncheckedAccount<'info>,
-    #[account(mut)]
-    pub transfer_authority: UncheckedAccount<'info>,
+#[derive(Accounts)]
+pub struct SetAmm<'info> {
+    #[account(mut, has_one = payer)]
+    pub vault: ProgramAccount<'info, Vault>,
 
-    // #[account(mut)]
-    // pub oracle: UncheckedAccount<'info>,
-    #[account(address = spl_token::ID)]
-    pub token_program: AccountInfo<'info>,
-    pub clock: Sysvar<'info, Clock>,
+    #[account(executable)]
+    pub amm_program: UncheckedAccount<'info>,
+
+    pub payer: Signer<'info>,
 }
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Debug)]
-pub struct Bump {
-    pub vault_bump: u8,
-    pub token_bump: u8,
-    pub mint_bump: u8,
+
+#[derive(Accounts)]
+pub struct SetMintLimit<'info> {
+    #[account(mut, has_one = payer)]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    pub payer: Signer<'info>,
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Debug)]
-pub struct InitParam {
-    pub bump: Bump,
-    pub percent: u64,
-    pub init_obligation: bool,
+#[derive(Accounts)]
+pub struct SetTimelock<'info> {
+    #[account(mut, has_one = payer)]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    pub payer: Signer<'info>,
 }
+
 #[derive(Accounts)]
-#[instruction(param: InitParam)]
-pub struct Init<'info> {
-    // For each token we have one vault
-    #[account(
-        init_if_needed,
-        seeds = [b"vault", mint_token.key().as_ref(), authority.key().as_ref()],
-        bump = param.bump.vault_bump,
-        payer = authority,
-        space = size_of::<Vault>() + 8,
-    )]
+pub struct SetPermissioned<'info> {
+    #[account(mut, has_one = payer)]
     pub vault: ProgramAccount<'info, Vault>,
 
-    #[account(
-        init_if_needed,
-        seeds = [b"vault_token", mint_token.key().as_ref(), vault.key().as_ref()],
-        bump = param.bump.token_bump,
-        token::mint = mint_token,
-        token::authority = vault,
-        payer = authority,
-    )]
-    pub vault_token: Account<'info, TokenAccount>,
+    pub payer: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8)]
+pub struct CreateRewardVault<'info> {
+    #[account(mut, has_one = payer)]
+    pub vault: ProgramAccount<'info, Vault>,
 
     #[account(
-        init_if_needed,
-        seeds = [b"synth_mint", mint_token.key().as_ref(), vault.key().as_ref()],
-        bump = param.bump.mint_bump,
-        mint::authority = vault,
-        mint::decimals = mint_token.decimals,
-        payer = authority,
+        init,
+        seeds = [b"reward_vault", vault.key().as_ref()],
+        bump = bump,
+        token::mint = reward_mint,
+        token::authority = vault,
+        payer = payer,
     )]
-    pub synth_mint: Account<'info, Mint>,
-    pub mint_token: Account<'info, Mint>,
+    pub reward_vault: Account<'info, TokenAccount>,
 
-    #[account(mut)]
-    pub authority: Signer<'info>,
+    pub reward_mint: Account<'info, Mint>,
 
     #[account(mut)]
-    pub obligation: UncheckedAccount<'info>,
-
-    pub lending_market: UncheckedAccount<'info>,
-    pub lending_program: UncheckedAccount<'info>,
+    pub payer: Signer<'info>,
 
     #[account(address = spl_token::ID)]
     pub token_program: AccountInfo<'info>,
@@ -140,137 +125,1841 @@ pub struct Init<'info> {
     #[account(address = system_program::ID)]
     pub system_program: AccountInfo<'info>,
     pub rent: Sysvar<'info, Rent>,
-    pub clock: Sysvar<'info, Clock>,
 }
 
-#[account]
-#[derive(Debug)]
-pub struct Vault {
-    pub bump: u8,
-    pub payer: Pubkey,
-    pub mint_token: Pubkey,  // The token this vault keep
-    pub vault_token: Pubkey, // PDA for this vault keep the token
-    pub synth_token: Pubkey, // LP token mint
-    pub percent: u64,
-    pub total_deposit: u64,
+#[derive(Accounts)]
+pub struct FundRewards<'info> {
+    #[account(mut, has_one = payer)]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(mut, address = vault.reward_vault)]
+    pub reward_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = payer_token.mint == vault.reward_mint)]
+    pub payer_token: Account<'info, TokenAccount>,
+
+    pub payer: Signer<'info>,
+
+    #[account(address = spl_token::ID)]
+    pub token_program: AccountInfo<'info>,
+    pub clock: Sysvar<'info, Clock>,
 }
 
+// Borrow fees owed to `owner` for the positions they referred
 #[account]
-pub struct Treasure {
-    pub current_deposit: u64,
-    pub current_borrow: u64,
+pub struct Referrer {
+    pub owner: Pubkey,
+    pub vault: Pubkey,
+    pub bump: u8,
+    pub accrued: u64, // Synth earned and not claimed yet
 }
 
 #[derive(Accounts)]
-#[instruction(bump: u8, amount: u64)]
-pub struct Deposit<'info> {
+#[instruction(bump: u8)]
+pub struct RegisterReferrer<'info> {
+    pub vault: ProgramAccount<'info, Vault>,
+
     #[account(
-        init_if_needed,
-        seeds = [b"treasure", vault.key().as_ref(), owner.key().as_ref()],
+        init,
+        seeds = [b"referrer", vault.key().as_ref(), owner.key().as_ref()],
         bump = bump,
-        payer = owner,
-        space = size_of::<Treasure>() + 8,
+        payer = payer,
+        space = size_of::<Referrer>() + 8,
     )]
-    pub treasure: ProgramAccount<'info, Treasure>,
+    pub referrer: ProgramAccount<'info, Referrer>,
 
-    #[account(mut, has_one = owner)]
-    pub user_token: Account<'info, TokenAccount>,
+    pub owner: Signer<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
 
-    #[account(mut, constraint = vault.mint_token == user_token.mint)]
+    #[account(address = system_program::ID)]
+    pub system_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimReferralFees<'info> {
+    #[account(
+        mut,
+        seeds = [b"referrer", vault.key().as_ref(), owner.key().as_ref()],
+        bump = referrer.bump,
+        has_one = vault,
+        has_one = owner,
+    )]
+    pub referrer: ProgramAccount<'info, Referrer>,
+
+    #[account(mut)]
     pub vault: ProgramAccount<'info, Vault>,
 
-    #[account(mut, constraint = vault_token.mint == vault.mint_token)]
-    pub vault_token: Account<'info, TokenAccount>,
+    #[account(mut, address = vault.synth_token)]
+    pub synth_mint: Account<'info, Mint>,
 
-    #[account(mut, constraint = user_synth.mint == vault.synth_token)]
-    pub user_synth: Account<'info, TokenAccount>,
+    // Any synth account the referrer chooses
+    #[account(mut, constraint = referrer_synth.mint == vault.synth_token)]
+    pub referrer_synth: Account<'info, TokenAccount>,
 
-    #[account(mut, signer)]
-    pub owner: AccountInfo<'info>,
+    pub owner: Signer<'info>,
 
     #[account(address = spl_token::ID)]
     pub token_program: AccountInfo<'info>,
-
-    #[account(address = system_program::ID)]
-    pub system_program: AccountInfo<'info>,
-
-    pub rent: Sysvar<'info, Rent>,
 }
 
 #[derive(Accounts)]
-pub struct Liquidate<'info> {
+pub struct ClaimRewards<'info> {
+    // Owner or position NFT holder, checked in the instruction
     #[account(
         mut,
-        close = owner,
+        seeds = [b"treasure", vault.key().as_ref(), treasure.owner.as_ref(), treasure.index.to_le_bytes().as_ref()],
+        bump = treasure.bump,
+        has_one = vault,
     )]
     pub treasure: ProgramAccount<'info, Treasure>,
 
-    #[account(mut, has_one = owner)]
-    pub user_token: Account<'info, TokenAccount>,
-
     #[account(mut)]
-    pub synth_mint: Account<'info, Mint>,
-
-    #[account(mut, constraint = vault.mint_token == user_token.mint)]
     pub vault: ProgramAccount<'info, Vault>,
 
-    #[account(mut, constraint = vault_token.mint == vault.mint_token)]
-    pub vault_token: Account<'info, TokenAccount>,
+    #[account(mut, address = vault.reward_vault)]
+    pub reward_vault: Account<'info, TokenAccount>,
 
-    #[account(mut, constraint = user_synth.mint == vault.synth_token)]
-    pub user_synth: Account<'info, TokenAccount>,
+    #[account(mut, constraint = user_reward.mint == vault.reward_mint)]
+    pub user_reward: Account<'info, TokenAccount>,
 
-    #[account(mut, signer)]
+    #[account(signer)]
     pub owner: AccountInfo<'info>,
 
     #[account(address = spl_token::ID)]
     pub token_program: AccountInfo<'info>,
+    pub clock: Sysvar<'info, Clock>,
+}
+
+// Lets `wallet` deposit and borrow in a permissioned vault
+#[account]
+pub struct AllowListEntry {
+    pub vault: Pubkey,
+    pub wallet: Pubkey,
+    pub bump: u8,
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8)]
+pub struct AllowWallet<'info> {
+    #[account(has_one = payer)]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    pub wallet: UncheckedAccount<'info>,
+
+    #[account(
+        init,
+        seeds = [b"allow_list", vault.key().as_ref(), wallet.key().as_ref()],
+        bump = bump,
+        payer = payer,
+        space = size_of::<AllowListEntry>() + 8,
+    )]
+    pub entry: ProgramAccount<'info, AllowListEntry>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
 
     #[account(address = system_program::ID)]
     pub system_program: AccountInfo<'info>,
+}
 
-    pub rent: Sysvar<'info, Rent>,
+#[derive(Accounts)]
+pub struct DisallowWallet<'info> {
+    #[account(has_one = payer)]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(mut, has_one = vault, close = payer)]
+    pub entry: ProgramAccount<'info, AllowListEntry>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+}
+
+// Parameter set waiting out the vault's timelock, one per vault
+#[account]
+pub struct PendingUpdate {
+    pub vault: Pubkey,
+    pub bump: u8,
+    pub percent: u64,
+    pub borrow_fee_bps: u64,
+    pub interest_rate: u64,
+    pub treasury: Pubkey,
+    pub timelock_delay: i64,
+    pub eta: i64, // Unix timestamp from which the update can be executed
 }
 
 #[derive(Accounts)]
-#[instruction(bump: u8, amount: u64)]
-pub struct Borrow<'info> {
+#[instruction(bump: u8)]
+pub struct QueueUpdate<'info> {
+    #[account(has_one = payer)]
+    pub vault: ProgramAccount<'info, Vault>,
+
     #[account(
-        init_if_needed,
-        seeds = [b"treasure", vault.key().as_ref(), owner.key().as_ref()],
+        init,
+        seeds = [b"pending_update", vault.key().as_ref()],
         bump = bump,
-        payer = owner,
-        space = size_of::<Treasure>() + 8,
+        payer = payer,
+        space = size_of::<PendingUpdate>() + 8,
     )]
-    pub treasure: ProgramAccount<'info, Treasure>,
+    pub pending_update: ProgramAccount<'info, PendingUpdate>,
+
+    #[account(constraint = treasury.mint == vault.synth_token)]
+    pub treasury: Account<'info, TokenAccount>,
 
     #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: AccountInfo<'info>,
+    pub clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteUpdate<'info> {
+    #[account(mut, has_one = payer)]
     pub vault: ProgramAccount<'info, Vault>,
 
-    #[account(mut, constraint = vault_token.mint == vault.mint_token)]
-    pub vault_token: Account<'info, TokenAccount>,
+    #[account(mut, has_one = vault, close = payer)]
+    pub pending_update: ProgramAccount<'info, PendingUpdate>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct CancelUpdate<'info> {
+    #[account(has_one = payer)]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(mut, has_one = vault, close = payer)]
+    pub pending_update: ProgramAccount<'info, PendingUpdate>,
 
     #[account(mut)]
+    pub payer: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CloseVault<'info> {
+    #[account(
+        mut,
+        has_one = payer,
+        constraint = vault.total_deposit == 0 || vault.counts_positions && vault.open_positions == 0,
+        close = payer,
+    )]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(mut, address = vault.vault_token)]
+    pub vault_token: UncheckedAccount<'info>,
+
+    #[account(mut, address = vault.synth_token, constraint = synth_mint.supply == 0)]
     pub synth_mint: Account<'info, Mint>,
 
-    #[account(mut, constraint = user_synth.mint == vault.synth_token)]
-    pub user_synth: Account<'info, TokenAccount>,
+    // Receives whatever is left in vault_token, checked in the instruction
+    #[account(mut)]
+    pub payer_token: UncheckedAccount<'info>,
 
-    #[account(mut, signer)]
-    pub owner: AccountInfo<'info>,
+    #[account(address = vault.mint_token)]
+    pub mint_token: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    // Pinned Port collateral, must be empty, the default address while the vault never lent
+    #[account(address = vault.collateral)]
+    pub collateral: UncheckedAccount<'info>,
+
+    // Port obligation, must hold no deposits or borrows, the default address until created
+    #[account(address = vault.obligation)]
+    pub obligation: UncheckedAccount<'info>,
+
+    // Closed once its leftover moved to payer_reward, the default address while the vault has no rewards
+    #[account(mut, address = vault.reward_vault)]
+    pub reward_vault: UncheckedAccount<'info>,
+
+    // Receives the rewards never emitted, checked in the instruction, ignored without rewards
+    #[account(mut)]
+    pub payer_reward: UncheckedAccount<'info>,
+
+    // Stability pool PDA and its token accounts, swept and closed, ignored while no pool exists
+    #[account(mut)]
+    pub pool: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub pool_synth: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub pool_collateral: UncheckedAccount<'info>,
 
     #[account(address = spl_token::ID)]
     pub token_program: AccountInfo<'info>,
 
-    #[account(address = system_program::ID)]
-    pub system_program: AccountInfo<'info>,
+    #[account(address = vault.mint_token_program())]
+    pub mint_token_program: UncheckedAccount<'info>,
 }
 
-#[event]
-pub struct InitVault {
-    pub payer: Pubkey,
-    pub mint_token: Pubkey,  // The token this vault keep
-    pub vault_token: Pubkey, // PDA for this vault keep the token
-    pub synth_token: Pubkey,
-    pub percent: u64,
+#[derive(Accounts)]
+pub struct SetSynthMetadata<'info> {
+    #[account(has_one = payer)]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(address = vault.synth_token)]
+    pub synth_mint: Account<'info, Mint>,
+
+    // Metaplex PDA of synth_mint, checked in the instruction
+    #[account(mut)]
+    pub metadata: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(address = crate::metadata::metadata_program::ID)]
+    pub metadata_program: UncheckedAccount<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: AccountInfo<'info>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct LendingCrank<'info> {
+    #[account(mut)]
+    pub vault: ProgramAccount<'info, Vault>,
+    #[account(address = vault.lending_program)]
+    pub port_program: UncheckedAccount<'info>,
+    #[account(mut, address = vault.vault_token)]
+    pub source_liquidity: UncheckedAccount<'info>,
+
+    #[account(mut, constraint = vault.payer == payer.key() )]
+    pub payer: Signer<'info>,
+
+    // Pinned on the first crank, must be vault.collateral after that
+    #[account(mut)]
+    pub destination_collateral: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub reserve: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub reserve_liquidity_supply: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub reserve_collateral_mint: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub lending_market: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub lending_market_authority: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub transfer_authority: UncheckedAccount<'info>,
+
+    // #[account(mut)]
+    // pub oracle: UncheckedAccount<'info>,
+    #[account(address = spl_token::ID)]
+    pub token_program: AccountInfo<'info>,
+    pub clock: Sysvar<'info, Clock>,
+}
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Debug)]
+pub struct Bump {
+    pub vault_bump: u8,
+    pub token_bump: u8,
+    pub mint_bump: u8,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Debug)]
+pub struct InitParam {
+    pub bump: Bump,
+    pub percent: u64,
+}
+#[derive(Accounts)]
+#[instruction(param: InitParam)]
+pub struct CreateVault<'info> {
+    // For each token we have one vault
+    #[account(
+        init,
+        seeds = [b"vault", mint_token.key().as_ref(), authority.key().as_ref()],
+        bump = param.bump.vault_bump,
+        payer = authority,
+        space = Vault::SPACE,
+    )]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(
+        init,
+        seeds = [b"vault_token", mint_token.key().as_ref(), vault.key().as_ref()],
+        bump = param.bump.token_bump,
+        token::mint = mint_token,
+        token::authority = vault,
+        payer = authority,
+    )]
+    pub vault_token: Account<'info, TokenAccount>,
+
+    #[account(
+        init,
+        seeds = [b"synth_mint", mint_token.key().as_ref(), vault.key().as_ref()],
+        bump = param.bump.mint_bump,
+        mint::authority = vault,
+        mint::decimals = mint_token.decimals,
+        payer = authority,
+    )]
+    pub synth_mint: Account<'info, Mint>,
+    pub mint_token: Account<'info, Mint>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub lending_market: UncheckedAccount<'info>,
+    pub lending_program: UncheckedAccount<'info>,
+
+    #[account(address = spl_token::ID)]
+    pub token_program: AccountInfo<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: AccountInfo<'info>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+#[instruction(param: InitParam, decimals: u8)]
+pub struct CreateVault2022<'info> {
+    #[account(
+        init,
+        seeds = [b"vault", mint_token.key().as_ref(), authority.key().as_ref()],
+        bump = param.bump.vault_bump,
+        payer = authority,
+        space = Vault::SPACE,
+    )]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    // Created in the instruction, sized for the extensions mint_token carries
+    #[account(
+        mut,
+        seeds = [b"vault_token", mint_token.key().as_ref(), vault.key().as_ref()],
+        bump = param.bump.token_bump,
+    )]
+    pub vault_token: UncheckedAccount<'info>,
+
+    // Synth stays a plain SPL mint
+    #[account(
+        init,
+        seeds = [b"synth_mint", mint_token.key().as_ref(), vault.key().as_ref()],
+        bump = param.bump.mint_bump,
+        mint::authority = vault,
+        mint::decimals = decimals,
+        payer = authority,
+    )]
+    pub synth_mint: Account<'info, Mint>,
+
+    #[account(owner = crate::token_2022::token_2022_program::ID)]
+    pub mint_token: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub lending_market: UncheckedAccount<'info>,
+    pub lending_program: UncheckedAccount<'info>,
+
+    #[account(address = spl_token::ID)]
+    pub token_program: AccountInfo<'info>,
+
+    #[account(address = crate::token_2022::token_2022_program::ID)]
+    pub mint_token_program: UncheckedAccount<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: AccountInfo<'info>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+#[instruction(nonce: Pubkey, ob_bump: u8)]
+pub struct CreateObligation<'info> {
+    #[account(mut, has_one = payer, constraint = vault.obligation == Pubkey::default())]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"obligation", nonce.as_ref(), vault.key().as_ref()],
+        bump = ob_bump,
+    )]
+    pub obligation: UncheckedAccount<'info>,
+
+    #[account(address = vault.lending_market)]
+    pub lending_market: UncheckedAccount<'info>,
+    #[account(address = vault.lending_program)]
+    pub lending_program: UncheckedAccount<'info>,
+
+    #[account(address = spl_token::ID)]
+    pub token_program: AccountInfo<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: AccountInfo<'info>,
+    pub rent: Sysvar<'info, Rent>,
+    pub clock: Sysvar<'info, Clock>,
+}
+
+#[account]
+#[derive(Debug, Default)]
+pub struct Vault {
+    pub bump: u8,
+    pub payer: Pubkey,
+    pub mint_token: Pubkey,  // The token this vault keep
+    pub vault_token: Pubkey, // PDA for this vault keep the token
+    pub synth_token: Pubkey, // LP token mint
+    pub percent: u64,
+    pub total_deposit: u64,
+    pub lending_program: Pubkey, // Port program the vault lends through
+    pub lending_market: Pubkey,  // Port market of the reserve valuing the vault
+    pub version: u8,
+    pub obligation: Pubkey, // Port obligation owned by the vault, default until created
+    pub token_program: Pubkey, // Program owning mint_token, default for vaults created before Token-2022
+    pub borrow_fee_bps: u64,   // Origination fee minted to treasury on each borrow
+    pub interest_rate: u64,    // Debt interest per second, scaled by INDEX_PRECISION
+    pub borrow_index: u128,    // Cumulative debt growth, zero until the first accrual means 1.0
+    pub last_accrual: i64,     // Unix timestamp the index was last moved to
+    pub treasury: Pubkey,      // Synth account receiving borrow fees
+    pub flash_loan: u64,       // Outstanding flash loan, only non zero inside a transaction
+    pub flash_mint: u64,       // Outstanding flash minted synth, same as flash_loan
+    pub amm_program: Pubkey,   // AMM leverage swaps synth through, default when disabled
+    pub mint_limit: u64,       // Most synth borrowing may mint per window, zero for no limit
+    pub mint_window: i64,      // Length of the mint limit window in seconds
+    pub window_start: i64,     // Unix timestamp the current window started at
+    pub window_minted: u64,    // Synth minted in the current window
+    pub previous_minted: u64,  // Synth minted in the window before
+    pub timelock_delay: i64,   // Seconds queued updates wait before executing, zero for immediate
+    pub permissioned: bool,    // Only allow-listed wallets may deposit and borrow
+    pub reward_mint: Pubkey,   // Token deposits earn, default until a reward vault is created
+    pub reward_vault: Pubkey,  // Reward tokens owned by the vault
+    pub reward_rate: u64,      // Reward tokens emitted per second across all deposits
+    pub reward_remaining: u64, // Funded rewards not emitted yet
+    pub reward_per_share: u128, // Rewards emitted per deposited unit, scaled by INDEX_PRECISION
+    pub reward_last_update: i64, // Unix timestamp reward_per_share was last moved to
+    pub deposit_index: u128, // Share of deposits left after Port losses, zero until the first loss means 1.0
+    pub collateral: Pubkey,  // Port collateral account pinned by the first lending_crank
+    pub counts_positions: bool, // Created at version 4 or later, so open_positions saw every position
+    pub open_positions: u64,    // Treasures not liquidated yet
+    pub referral_accrued: u64,  // Referral fees accrued and not claimed yet, counted from version 4
+}
+
+impl Vault {
+    // Version 1 accounts were sized before the rewards were appended and may end early
+    pub const VERSION: u8 = 4;
+    // Fixed account size, bump VERSION for each appended field so migrate_vault can tell layouts apart
+    pub const SPACE: usize = 8 + 1024;
+    // Where `version` sits in the account data, every versioned layout keeps it there
+    pub const VERSION_OFFSET: usize = 8 + 1 + 32 * 4 + 8 * 2 + 32 * 2;
+
+    pub fn mint_token_program(&self) -> Pubkey {
+        if self.token_program == Pubkey::default() {
+            spl_token::ID
+        } else {
+            self.token_program
+        }
+    }
+
+    /// Borrow index as of `now`, without writing it back.
+    pub fn current_borrow_index(&self, now: i64) -> std::result::Result<u128, ProgramError> {
+        let index = if self.borrow_index == 0 {
+            Parameters::INDEX_PRECISION
+        } else {
+            self.borrow_index
+        };
+        if self.interest_rate == 0 || self.last_accrual == 0 {
+            return Ok(index);
+        }
+        let elapsed = now.saturating_sub(self.last_accrual).max(0) as u64;
+        Parameters::accrue_index(index, self.interest_rate, elapsed)
+            .ok_or_else(|| VaultError::MathOverflow.into())
+    }
+
+    pub fn current_deposit_index(&self) -> u128 {
+        if self.deposit_index == 0 {
+            Parameters::INDEX_PRECISION
+        } else {
+            self.deposit_index
+        }
+    }
+
+    pub fn accrue_interest(&mut self, now: i64) -> ProgramResult {
+        self.borrow_index = self.current_borrow_index(now)?;
+        self.last_accrual = now;
+        Ok(())
+    }
+
+    /// Counts `amount` against the mint limit, failing once the last `mint_window` seconds
+    /// minted more than `mint_limit`.
+    ///
+    /// The window before the current one counts for the part of it still inside the last
+    /// `mint_window` seconds, assuming it minted evenly.
+    pub fn record_mint(&mut self, amount: u64, now: i64) -> ProgramResult {
+        if self.mint_limit == 0 {
+            return Ok(());
+        }
+        // A clock behind window_start counts as the window's first second
+        let elapsed = now.saturating_sub(self.window_start).max(0);
+        let two_windows = self
+            .mint_window
+            .checked_mul(2)
+            .ok_or(VaultError::MathOverflow)?;
+        if elapsed >= two_windows {
+            self.previous_minted = 0;
+            self.window_minted = 0;
+            self.window_start = now;
+        } else if elapsed >= self.mint_window {
+            self.previous_minted = self.window_minted;
+            self.window_minted = 0;
+            self.window_start += self.mint_window;
+        }
+
+        let elapsed = now.saturating_sub(self.window_start).max(0) as u128;
+        let window = self.mint_window as u128;
+        let previous = self.previous_minted as u128 * (window - elapsed) / window;
+        let minted = self
+            .window_minted
+            .checked_add(amount)
+            .ok_or(VaultError::MathOverflow)?;
+        if previous + minted as u128 > self.mint_limit as u128 {
+            return Err(VaultError::MintLimitExceeded.into());
+        }
+        self.window_minted = minted;
+        Ok(())
+    }
+}
+
+#[account]
+pub struct Treasure {
+    pub owner: Pubkey,
+    pub vault: Pubkey,
+    pub bump: u8,
+    pub index: u64, // Position number among the owner's positions in this vault
+    pub position_mint: Pubkey, // NFT controlling the position, default while not tokenized
+    pub current_deposit: u64,
+    pub current_borrow: u64,
+    pub version: u8,
+    pub borrow_index: u128, // Vault borrow index current_borrow was last accrued at
+    pub reward_per_share: u128, // Vault reward_per_share current_deposit last earned up to
+    pub reward_owed: u64,   // Rewards earned and not claimed yet
+    pub deposit_index: u128, // Vault deposit index current_deposit was last cut at
+    pub referrer: Pubkey,   // Referrer PDA sharing the borrow fees, default when not referred
+}
+
+impl Treasure {
+    pub const VERSION: u8 = 1;
+    // Fixed account size, version 1 treasures are allocated at it so appended fields fit without reallocating
+    pub const SPACE: usize = 8 + 320;
+}
+
+// Layouts from before accounts carried a version, only read by the migrate instructions
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Debug)]
+pub struct VaultV0 {
+    pub bump: u8,
+    pub payer: Pubkey,
+    pub mint_token: Pubkey,
+    pub vault_token: Pubkey,
+    pub synth_token: Pubkey,
+    pub percent: u64,
+    pub total_deposit: u64,
+}
+
+// Seeded by [b"treasure", vault, owner], one per owner
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Debug)]
+pub struct TreasureV0 {
+    pub current_deposit: u64,
+    pub current_borrow: u64,
+}
+
+// Counts the positions an owner opened in a vault, the next position uses `count` as index
+#[account]
+pub struct UserPositions {
+    pub owner: Pubkey,
+    pub vault: Pubkey,
+    pub bump: u8,
+    pub count: u64,
+}
+
+#[derive(Accounts)]
+#[instruction(positions_bump: u8, treasure_bump: u8, index: u64)]
+pub struct OpenPosition<'info> {
+    #[account(
+        init_if_needed,
+        seeds = [b"user_positions", vault.key().as_ref(), owner.key().as_ref()],
+        bump = positions_bump,
+        payer = owner,
+        space = size_of::<UserPositions>() + 8,
+    )]
+    pub user_positions: ProgramAccount<'info, UserPositions>,
+
+    #[account(
+        init,
+        seeds = [b"treasure", vault.key().as_ref(), owner.key().as_ref(), index.to_le_bytes().as_ref()],
+        bump = treasure_bump,
+        payer = owner,
+        space = Treasure::SPACE,
+    )]
+    pub treasure: ProgramAccount<'info, Treasure>,
+
+    #[account(mut)]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(mut, signer)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: AccountInfo<'info>,
+
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+#[instruction(positions_bump: u8, treasure_bump: u8, index: u64)]
+pub struct TransferPosition<'info> {
+    #[account(
+        mut,
+        seeds = [b"treasure", vault.key().as_ref(), owner.key().as_ref(), treasure.index.to_le_bytes().as_ref()],
+        bump = treasure.bump,
+        has_one = owner,
+        has_one = vault,
+        constraint = treasure.position_mint == Pubkey::default() @ VaultError::TokenizedPosition,
+        close = owner,
+    )]
+    pub treasure: ProgramAccount<'info, Treasure>,
+
+    #[account(
+        init_if_needed,
+        seeds = [b"user_positions", vault.key().as_ref(), new_owner.key().as_ref()],
+        bump = positions_bump,
+        payer = new_owner,
+        space = size_of::<UserPositions>() + 8,
+    )]
+    pub new_user_positions: ProgramAccount<'info, UserPositions>,
+
+    #[account(
+        init,
+        seeds = [b"treasure", vault.key().as_ref(), new_owner.key().as_ref(), index.to_le_bytes().as_ref()],
+        bump = treasure_bump,
+        payer = new_owner,
+        space = Treasure::SPACE,
+    )]
+    pub new_treasure: ProgramAccount<'info, Treasure>,
+
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(mut, signer)]
+    pub owner: AccountInfo<'info>,
+
+    // Signing is the recipient's consent, it also pays for the new position
+    #[account(mut, signer)]
+    pub new_owner: AccountInfo<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: AccountInfo<'info>,
+
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+#[instruction(mint_bump: u8)]
+pub struct TokenizePosition<'info> {
+    #[account(
+        mut,
+        seeds = [b"treasure", vault.key().as_ref(), owner.key().as_ref(), treasure.index.to_le_bytes().as_ref()],
+        bump = treasure.bump,
+        has_one = owner,
+        has_one = vault,
+        constraint = treasure.position_mint == Pubkey::default(),
+    )]
+    pub treasure: ProgramAccount<'info, Treasure>,
+
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(
+        init,
+        seeds = [b"position_mint", treasure.key().as_ref()],
+        bump = mint_bump,
+        mint::authority = vault,
+        mint::decimals = 0,
+        payer = owner,
+    )]
+    pub position_mint: Account<'info, Mint>,
+
+    #[account(
+        init,
+        associated_token::mint = position_mint,
+        associated_token::authority = owner,
+        payer = owner,
+    )]
+    pub owner_position_token: Account<'info, TokenAccount>,
+
+    #[account(mut, signer)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(address = spl_token::ID)]
+    pub token_program: AccountInfo<'info>,
+
+    #[account(address = spl_associated_token_account::ID)]
+    pub associated_token_program: AccountInfo<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: AccountInfo<'info>,
+
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateVault<'info> {
+    // Old layouts don't deserialize as Vault, checked in the instruction
+    #[account(mut, owner = crate::ID)]
+    pub vault: UncheckedAccount<'info>,
+
+    #[account(mut, signer)]
+    pub payer: AccountInfo<'info>,
+
+    // Only read when migrating from version 0, later versions already store them
+    pub lending_market: UncheckedAccount<'info>,
+    pub lending_program: UncheckedAccount<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: AccountInfo<'info>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+#[instruction(positions_bump: u8, treasure_bump: u8, index: u64, old_bump: u8)]
+pub struct MigrateTreasure<'info> {
+    // Version 0 treasure, moved to an indexed PDA and closed
+    #[account(
+        mut,
+        owner = crate::ID,
+        seeds = [b"treasure", vault.key().as_ref(), owner.key().as_ref()],
+        bump = old_bump,
+    )]
+    pub old_treasure: UncheckedAccount<'info>,
+
+    #[account(
+        init_if_needed,
+        seeds = [b"user_positions", vault.key().as_ref(), owner.key().as_ref()],
+        bump = positions_bump,
+        payer = owner,
+        space = size_of::<UserPositions>() + 8,
+    )]
+    pub user_positions: ProgramAccount<'info, UserPositions>,
+
+    #[account(
+        init,
+        seeds = [b"treasure", vault.key().as_ref(), owner.key().as_ref(), index.to_le_bytes().as_ref()],
+        bump = treasure_bump,
+        payer = owner,
+        space = Treasure::SPACE,
+    )]
+    pub treasure: ProgramAccount<'info, Treasure>,
+
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(mut, signer)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: AccountInfo<'info>,
+
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct Deposit<'info> {
+    #[account(
+        mut,
+        seeds = [b"treasure", vault.key().as_ref(), owner.key().as_ref(), treasure.index.to_le_bytes().as_ref()],
+        bump = treasure.bump,
+        has_one = owner,
+        has_one = vault,
+    )]
+    pub treasure: ProgramAccount<'info, Treasure>,
+
+    // Owner's mint_token account, checked in the instruction
+    #[account(mut)]
+    pub user_token: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(mut, address = vault.vault_token)]
+    pub vault_token: UncheckedAccount<'info>,
+
+    #[account(address = vault.mint_token)]
+    pub mint_token: UncheckedAccount<'info>,
+
+    #[account(mut, signer)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(address = vault.mint_token_program())]
+    pub mint_token_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DepositFor<'info> {
+    // Beneficiary's position, the payer needs no rights over it
+    #[account(
+        mut,
+        seeds = [b"treasure", vault.key().as_ref(), treasure.owner.as_ref(), treasure.index.to_le_bytes().as_ref()],
+        bump = treasure.bump,
+        has_one = vault,
+    )]
+    pub treasure: ProgramAccount<'info, Treasure>,
+
+    // Payer's mint_token account, checked in the instruction
+    #[account(mut)]
+    pub payer_token: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(mut, address = vault.vault_token)]
+    pub vault_token: UncheckedAccount<'info>,
+
+    #[account(address = vault.mint_token)]
+    pub mint_token: UncheckedAccount<'info>,
+
+    #[account(signer)]
+    pub payer: AccountInfo<'info>,
+
+    #[account(address = vault.mint_token_program())]
+    pub mint_token_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct Liquidate<'info> {
+    // Owner or position NFT holder, checked in the instruction
+    #[account(
+        mut,
+        seeds = [b"treasure", vault.key().as_ref(), treasure.owner.as_ref(), treasure.index.to_le_bytes().as_ref()],
+        bump = treasure.bump,
+        has_one = vault,
+        close = owner,
+    )]
+    pub treasure: ProgramAccount<'info, Treasure>,
+
+    // Owner's mint_token account, checked in the instruction
+    #[account(mut)]
+    pub user_token: UncheckedAccount<'info>,
+
+    #[account(mut, address = vault.synth_token)]
+    pub synth_mint: Account<'info, Mint>,
+
+    #[account(mut)]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(mut, address = vault.vault_token)]
+    pub vault_token: UncheckedAccount<'info>,
+
+    #[account(address = vault.mint_token)]
+    pub mint_token: UncheckedAccount<'info>,
+
+    #[account(mut, constraint = user_synth.mint == vault.synth_token)]
+    pub user_synth: Account<'info, TokenAccount>,
+
+    #[account(mut, signer)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(address = spl_token::ID)]
+    pub token_program: AccountInfo<'info>,
+
+    #[account(address = vault.mint_token_program())]
+    pub mint_token_program: UncheckedAccount<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: AccountInfo<'info>,
+
+    pub rent: Sysvar<'info, Rent>,
+    pub clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct Borrow<'info> {
+    // Owner or position NFT holder, checked in the instruction
+    #[account(
+        mut,
+        seeds = [b"treasure", vault.key().as_ref(), treasure.owner.as_ref(), treasure.index.to_le_bytes().as_ref()],
+        bump = treasure.bump,
+        has_one = vault,
+    )]
+    pub treasure: ProgramAccount<'info, Treasure>,
+
+    #[account(mut)]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(mut, address = vault.vault_token)]
+    pub vault_token: UncheckedAccount<'info>,
+
+    #[account(mut, address = vault.synth_token)]
+    pub synth_mint: Account<'info, Mint>,
+
+    #[account(mut, constraint = user_synth.mint == vault.synth_token)]
+    pub user_synth: Account<'info, TokenAccount>,
+
+    // Vault treasury, only checked and credited when the vault charges a borrow fee
+    #[account(mut)]
+    pub treasury: UncheckedAccount<'info>,
+
+    #[account(mut, signer)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(address = spl_token::ID)]
+    pub token_program: AccountInfo<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: AccountInfo<'info>,
+    pub clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct Repay<'info> {
+    // Owner or position NFT holder, checked in the instruction
+    #[account(
+        mut,
+        seeds = [b"treasure", vault.key().as_ref(), treasure.owner.as_ref(), treasure.index.to_le_bytes().as_ref()],
+        bump = treasure.bump,
+        has_one = vault,
+    )]
+    pub treasure: ProgramAccount<'info, Treasure>,
+
+    #[account(mut)]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(mut, address = vault.synth_token)]
+    pub synth_mint: Account<'info, Mint>,
+
+    #[account(mut, constraint = user_synth.mint == vault.synth_token)]
+    pub user_synth: Account<'info, TokenAccount>,
+
+    #[account(signer)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(address = spl_token::ID)]
+    pub token_program: AccountInfo<'info>,
+    pub clock: Sysvar<'info, Clock>,
+}
+
+// Shared by deposit_and_borrow and repay_and_withdraw
+#[derive(Accounts)]
+pub struct Zap<'info> {
+    // Owner or position NFT holder, checked in the instruction
+    #[account(
+        mut,
+        seeds = [b"treasure", vault.key().as_ref(), treasure.owner.as_ref(), treasure.index.to_le_bytes().as_ref()],
+        bump = treasure.bump,
+        has_one = vault,
+    )]
+    pub treasure: ProgramAccount<'info, Treasure>,
+
+    #[account(mut)]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(mut, address = vault.vault_token)]
+    pub vault_token: UncheckedAccount<'info>,
+
+    #[account(address = vault.mint_token)]
+    pub mint_token: UncheckedAccount<'info>,
+
+    // Signer's mint_token account, checked in the instruction
+    #[account(mut)]
+    pub user_token: UncheckedAccount<'info>,
+
+    #[account(mut, address = vault.synth_token)]
+    pub synth_mint: Account<'info, Mint>,
+
+    #[account(mut, constraint = user_synth.mint == vault.synth_token)]
+    pub user_synth: Account<'info, TokenAccount>,
+
+    // Vault treasury, only checked and credited when the vault charges a borrow fee
+    #[account(mut)]
+    pub treasury: UncheckedAccount<'info>,
+
+    #[account(signer)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(address = spl_token::ID)]
+    pub token_program: AccountInfo<'info>,
+
+    #[account(address = vault.mint_token_program())]
+    pub mint_token_program: UncheckedAccount<'info>,
+    pub clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct Leverage<'info> {
+    // Owner or position NFT holder, checked in the instruction
+    #[account(
+        mut,
+        seeds = [b"treasure", vault.key().as_ref(), treasure.owner.as_ref(), treasure.index.to_le_bytes().as_ref()],
+        bump = treasure.bump,
+        has_one = vault,
+    )]
+    pub treasure: ProgramAccount<'info, Treasure>,
+
+    #[account(mut)]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(mut, address = vault.vault_token)]
+    pub vault_token: UncheckedAccount<'info>,
+
+    #[account(address = vault.mint_token)]
+    pub mint_token: UncheckedAccount<'info>,
+
+    // Receives the swap output before it is deposited, checked in the instruction
+    #[account(mut)]
+    pub user_token: UncheckedAccount<'info>,
+
+    #[account(mut, address = vault.synth_token)]
+    pub synth_mint: Account<'info, Mint>,
+
+    #[account(mut, constraint = user_synth.mint == vault.synth_token)]
+    pub user_synth: Account<'info, TokenAccount>,
+
+    // Vault treasury, only checked and credited when the vault charges a borrow fee
+    #[account(mut)]
+    pub treasury: UncheckedAccount<'info>,
+
+    #[account(signer)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(address = vault.amm_program, constraint = vault.amm_program != Pubkey::default())]
+    pub amm_program: UncheckedAccount<'info>,
+
+    #[account(address = spl_token::ID)]
+    pub token_program: AccountInfo<'info>,
+
+    #[account(address = vault.mint_token_program())]
+    pub mint_token_program: UncheckedAccount<'info>,
+    pub clock: Sysvar<'info, Clock>,
+}
+
+// Synth deposits cancelling liquidated debt, one per vault
+#[account]
+pub struct StabilityPool {
+    pub vault: Pubkey,
+    pub bump: u8,
+    pub pool_synth: Pubkey,      // Deposited synth, owned by the vault
+    pub pool_collateral: Pubkey, // Seized collateral waiting to be claimed, owned by the vault
+    pub total_deposits: u64,
+    pub product: u128, // What one deposited unit is worth now, scaled by INDEX_PRECISION
+    pub sum: u128,     // Collateral earned per deposited unit this scale, scaled the same way
+    pub scale: u64,    // Times the product was scaled up by SCALE_FACTOR in this epoch
+    pub epoch: u64,    // Times liquidations emptied the pool
+}
+
+#[account]
+pub struct StabilityDeposit {
+    pub owner: Pubkey,
+    pub pool: Pubkey,
+    pub bump: u8,
+    pub amount: u64, // Synth deposited as of the snapshot
+    pub snapshot_product: u128,
+    pub snapshot_sum: u128,
+    pub snapshot_scale: u64,
+    pub snapshot_epoch: u64,
+}
+
+// Sum a pool's epoch and scale ended at, for deposits snapshotted before it moved on
+#[account]
+pub struct StabilityScale {
+    pub sum: u128,
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8, synth_bump: u8, collateral_bump: u8)]
+pub struct CreateStabilityPool<'info> {
+    #[account(has_one = payer)]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(
+        init,
+        seeds = [b"stability_pool", vault.key().as_ref()],
+        bump = bump,
+        payer = payer,
+        space = size_of::<StabilityPool>() + 8,
+    )]
+    pub pool: ProgramAccount<'info, StabilityPool>,
+
+    #[account(
+        init,
+        seeds = [b"pool_synth", vault.key().as_ref()],
+        bump = synth_bump,
+        token::mint = synth_mint,
+        token::authority = vault,
+        payer = payer,
+    )]
+    pub pool_synth: Account<'info, TokenAccount>,
+
+    // Plain SPL only, Token-2022 vaults can't have a pool yet
+    #[account(
+        init,
+        seeds = [b"pool_collateral", vault.key().as_ref()],
+        bump = collateral_bump,
+        token::mint = mint_token,
+        token::authority = vault,
+        payer = payer,
+    )]
+    pub pool_collateral: Account<'info, TokenAccount>,
+
+    #[account(address = vault.synth_token)]
+    pub synth_mint: Account<'info, Mint>,
+
+    #[account(address = vault.mint_token)]
+    pub mint_token: Account<'info, Mint>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(address = spl_token::ID)]
+    pub token_program: AccountInfo<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: AccountInfo<'info>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8)]
+pub struct ProvideToPool<'info> {
+    #[account(
+        mut,
+        seeds = [b"stability_pool", vault.key().as_ref()],
+        bump = pool.bump,
+        has_one = vault,
+    )]
+    pub pool: ProgramAccount<'info, StabilityPool>,
+
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(
+        init_if_needed,
+        seeds = [b"stability_deposit", pool.key().as_ref(), owner.key().as_ref()],
+        bump = bump,
+        payer = owner,
+        space = size_of::<StabilityDeposit>() + 8,
+    )]
+    pub deposit: ProgramAccount<'info, StabilityDeposit>,
+
+    #[account(mut, address = pool.pool_synth)]
+    pub pool_synth: Account<'info, TokenAccount>,
+
+    #[account(mut, address = pool.pool_collateral)]
+    pub pool_collateral: Account<'info, TokenAccount>,
+
+    // Scale PDAs of the deposit's snapshot and the scale after, checked in the instruction
+    pub snapshot_scale: UncheckedAccount<'info>,
+    pub next_scale: UncheckedAccount<'info>,
+
+    #[account(mut, constraint = user_synth.mint == vault.synth_token)]
+    pub user_synth: Account<'info, TokenAccount>,
+
+    // Receives collateral earned by the deposit
+    #[account(mut, constraint = user_token.mint == vault.mint_token)]
+    pub user_token: Account<'info, TokenAccount>,
+
+    #[account(address = vault.mint_token)]
+    pub mint_token: UncheckedAccount<'info>,
+
+    #[account(mut, signer)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(address = spl_token::ID)]
+    pub token_program: AccountInfo<'info>,
+
+    #[account(address = vault.mint_token_program())]
+    pub mint_token_program: UncheckedAccount<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: AccountInfo<'info>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawFromPool<'info> {
+    #[account(
+        mut,
+        seeds = [b"stability_pool", vault.key().as_ref()],
+        bump = pool.bump,
+        has_one = vault,
+    )]
+    pub pool: ProgramAccount<'info, StabilityPool>,
+
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(
+        mut,
+        seeds = [b"stability_deposit", pool.key().as_ref(), owner.key().as_ref()],
+        bump = deposit.bump,
+        has_one = pool,
+        has_one = owner,
+    )]
+    pub deposit: ProgramAccount<'info, StabilityDeposit>,
+
+    #[account(mut, address = pool.pool_synth)]
+    pub pool_synth: Account<'info, TokenAccount>,
+
+    #[account(mut, address = pool.pool_collateral)]
+    pub pool_collateral: Account<'info, TokenAccount>,
+
+    // Scale PDAs of the deposit's snapshot and the scale after, checked in the instruction
+    pub snapshot_scale: UncheckedAccount<'info>,
+    pub next_scale: UncheckedAccount<'info>,
+
+    #[account(mut, constraint = user_synth.mint == vault.synth_token)]
+    pub user_synth: Account<'info, TokenAccount>,
+
+    // Receives collateral earned by the deposit
+    #[account(mut, constraint = user_token.mint == vault.mint_token)]
+    pub user_token: Account<'info, TokenAccount>,
+
+    #[account(address = vault.mint_token)]
+    pub mint_token: UncheckedAccount<'info>,
+
+    #[account(signer)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(address = spl_token::ID)]
+    pub token_program: AccountInfo<'info>,
+
+    #[account(address = vault.mint_token_program())]
+    pub mint_token_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct LiquidateToPool<'info> {
+    #[account(
+        mut,
+        seeds = [b"treasure", vault.key().as_ref(), treasure.owner.as_ref(), treasure.index.to_le_bytes().as_ref()],
+        bump = treasure.bump,
+        has_one = vault,
+    )]
+    pub treasure: ProgramAccount<'info, Treasure>,
+
+    #[account(mut)]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(
+        mut,
+        seeds = [b"stability_pool", vault.key().as_ref()],
+        bump = pool.bump,
+        has_one = vault,
+    )]
+    pub pool: ProgramAccount<'info, StabilityPool>,
+
+    // Scale PDA of the pool's current epoch and scale, created if the liquidation ends them
+    #[account(mut)]
+    pub ended_scale: UncheckedAccount<'info>,
+
+    #[account(mut, address = pool.pool_synth)]
+    pub pool_synth: Account<'info, TokenAccount>,
+
+    #[account(mut, address = pool.pool_collateral)]
+    pub pool_collateral: Account<'info, TokenAccount>,
+
+    #[account(mut, address = vault.synth_token)]
+    pub synth_mint: Account<'info, Mint>,
+
+    #[account(mut, address = vault.vault_token)]
+    pub vault_token: UncheckedAccount<'info>,
+
+    #[account(address = vault.mint_token)]
+    pub mint_token: UncheckedAccount<'info>,
+
+    // Pays for the ended scale's PDA
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(address = spl_token::ID)]
+    pub token_program: AccountInfo<'info>,
+
+    #[account(address = vault.mint_token_program())]
+    pub mint_token_program: UncheckedAccount<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: AccountInfo<'info>,
+    pub rent: Sysvar<'info, Rent>,
+    pub clock: Sysvar<'info, Clock>,
+}
+
+// Lets `delegate` borrow against and optionally repay `treasure`, never withdraw from it
+#[account]
+pub struct Delegation {
+    pub treasure: Pubkey,
+    pub delegate: Pubkey,
+    pub bump: u8,
+    pub borrow_limit: u64, // Total synth the delegate may borrow
+    pub borrowed: u64,     // Synth borrowed by the delegate so far
+    pub expires_at: i64,   // Unix timestamp
+    pub can_repay: bool,
+    pub approver: Pubkey, // Owner or NFT holder who approved, the delegation lapses once they no longer control the position
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8)]
+pub struct ApproveDelegate<'info> {
+    // Owner or position NFT holder, checked in the instruction
+    #[account(
+        seeds = [b"treasure", vault.key().as_ref(), treasure.owner.as_ref(), treasure.index.to_le_bytes().as_ref()],
+        bump = treasure.bump,
+        has_one = vault,
+    )]
+    pub treasure: ProgramAccount<'info, Treasure>,
+
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(
+        init_if_needed,
+        seeds = [b"delegation", treasure.key().as_ref(), delegate.key().as_ref()],
+        bump = bump,
+        payer = owner,
+        space = size_of::<Delegation>() + 8,
+    )]
+    pub delegation: ProgramAccount<'info, Delegation>,
+
+    pub delegate: UncheckedAccount<'info>,
+
+    #[account(mut, signer)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: AccountInfo<'info>,
+
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct RevokeDelegate<'info> {
+    // Owner or position NFT holder, checked in the instruction
+    #[account(
+        seeds = [b"treasure", treasure.vault.as_ref(), treasure.owner.as_ref(), treasure.index.to_le_bytes().as_ref()],
+        bump = treasure.bump,
+    )]
+    pub treasure: ProgramAccount<'info, Treasure>,
+
+    #[account(
+        mut,
+        seeds = [b"delegation", treasure.key().as_ref(), delegation.delegate.as_ref()],
+        bump = delegation.bump,
+        has_one = treasure,
+        close = owner,
+    )]
+    pub delegation: ProgramAccount<'info, Delegation>,
+
+    #[account(mut, signer)]
+    pub owner: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DelegatedBorrow<'info> {
+    #[account(
+        mut,
+        seeds = [b"treasure", vault.key().as_ref(), treasure.owner.as_ref(), treasure.index.to_le_bytes().as_ref()],
+        bump = treasure.bump,
+        has_one = vault,
+    )]
+    pub treasure: ProgramAccount<'info, Treasure>,
+
+    #[account(
+        mut,
+        seeds = [b"delegation", treasure.key().as_ref(), delegate.key().as_ref()],
+        bump = delegation.bump,
+        has_one = treasure,
+        has_one = delegate,
+    )]
+    pub delegation: ProgramAccount<'info, Delegation>,
+
+    #[account(mut)]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(mut, address = vault.synth_token)]
+    pub synth_mint: Account<'info, Mint>,
+
+    // Any synth account the delegate chooses
+    #[account(mut, constraint = receiver_synth.mint == vault.synth_token)]
+    pub receiver_synth: Account<'info, TokenAccount>,
+
+    // Vault treasury, only checked and credited when the vault charges a borrow fee
+    #[account(mut)]
+    pub treasury: UncheckedAccount<'info>,
+
+    #[account(signer)]
+    pub delegate: AccountInfo<'info>,
+
+    #[account(address = spl_token::ID)]
+    pub token_program: AccountInfo<'info>,
+    pub clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct DelegatedRepay<'info> {
+    #[account(
+        mut,
+        seeds = [b"treasure", vault.key().as_ref(), treasure.owner.as_ref(), treasure.index.to_le_bytes().as_ref()],
+        bump = treasure.bump,
+        has_one = vault,
+    )]
+    pub treasure: ProgramAccount<'info, Treasure>,
+
+    #[account(
+        seeds = [b"delegation", treasure.key().as_ref(), delegate.key().as_ref()],
+        bump = delegation.bump,
+        has_one = treasure,
+        has_one = delegate,
+    )]
+    pub delegation: ProgramAccount<'info, Delegation>,
+
+    #[account(mut)]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(mut, address = vault.synth_token)]
+    pub synth_mint: Account<'info, Mint>,
+
+    #[account(mut, constraint = delegate_synth.mint == vault.synth_token)]
+    pub delegate_synth: Account<'info, TokenAccount>,
+
+    #[account(signer)]
+    pub delegate: AccountInfo<'info>,
+
+    #[account(address = spl_token::ID)]
+    pub token_program: AccountInfo<'info>,
+    pub clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct GetPosition<'info> {
+    #[account(
+        seeds = [b"treasure", vault.key().as_ref(), owner.key().as_ref(), treasure.index.to_le_bytes().as_ref()],
+        bump = treasure.bump,
+        has_one = owner,
+        has_one = vault,
+    )]
+    pub treasure: ProgramAccount<'info, Treasure>,
+
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(address = vault.vault_token)]
+    pub vault_token: UncheckedAccount<'info>,
+
+    // Port collateral pinned by lending_crank, the default address while the vault never lent
+    #[account(address = vault.collateral)]
+    pub collateral: UncheckedAccount<'info>,
+    pub reserve: UncheckedAccount<'info>,
+
+    pub owner: UncheckedAccount<'info>,
+    pub clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct RecordLoss<'info> {
+    #[account(mut, has_one = payer)]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(address = vault.vault_token)]
+    pub vault_token: UncheckedAccount<'info>,
+
+    // Port collateral pinned by lending_crank, the default address while the vault never lent
+    #[account(address = vault.collateral)]
+    pub collateral: UncheckedAccount<'info>,
+    pub reserve: UncheckedAccount<'info>,
+
+    // Cuts every deposit, only the vault's authority decides a loss is real
+    pub payer: Signer<'info>,
+
+    pub clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct FlashBorrow<'info> {
+    // First account, flash_repay is matched on it
+    #[account(mut)]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(mut, address = vault.vault_token)]
+    pub vault_token: UncheckedAccount<'info>,
+
+    #[account(address = vault.mint_token)]
+    pub mint_token: UncheckedAccount<'info>,
+
+    // Any mint_token account
+    #[account(mut)]
+    pub receiver_token: UncheckedAccount<'info>,
+
+    #[account(address = vault.mint_token_program())]
+    pub mint_token_program: UncheckedAccount<'info>,
+
+    #[account(address = sysvar::instructions::ID)]
+    pub instructions: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct FlashRepay<'info> {
+    #[account(mut)]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(mut, address = vault.vault_token)]
+    pub vault_token: UncheckedAccount<'info>,
+
+    #[account(address = vault.mint_token)]
+    pub mint_token: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub repayer_token: UncheckedAccount<'info>,
+
+    #[account(signer)]
+    pub repayer: AccountInfo<'info>,
+
+    #[account(address = vault.mint_token_program())]
+    pub mint_token_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct FlashMint<'info> {
+    // First account, flash_burn is matched on it
+    #[account(mut)]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(mut, address = vault.synth_token)]
+    pub synth_mint: Account<'info, Mint>,
+
+    #[account(mut, constraint = receiver_synth.mint == vault.synth_token)]
+    pub receiver_synth: Account<'info, TokenAccount>,
+
+    #[account(address = spl_token::ID)]
+    pub token_program: AccountInfo<'info>,
+
+    #[account(address = sysvar::instructions::ID)]
+    pub instructions: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct FlashBurn<'info> {
+    #[account(mut)]
+    pub vault: ProgramAccount<'info, Vault>,
+
+    #[account(mut, address = vault.synth_token)]
+    pub synth_mint: Account<'info, Mint>,
+
+    #[account(mut, constraint = payer_synth.mint == vault.synth_token)]
+    pub payer_synth: Account<'info, TokenAccount>,
+
+    // Vault treasury, only checked and credited when the vault has one
+    #[account(mut)]
+    pub treasury: UncheckedAccount<'info>,
+
+    #[account(signer)]
+    pub payer: AccountInfo<'info>,
+
+    #[account(address = spl_token::ID)]
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Debug)]
+pub struct PositionView {
+    pub deposit_value: u64,     // Deposit plus accrued yield, in mint_token
+    pub debt: u64,              // Outstanding synth
+    pub max_borrow: u64,        // Synth that can still be borrowed, net of the borrow fee
+    pub health_ratio: u64,      // Borrow limit over debt, 10_000 = at the limit
+    pub accrued_repayment: u64, // Yield earned towards paying the debt
+}
+
+#[event]
+pub struct InitVault {
+    pub payer: Pubkey,
+    pub mint_token: Pubkey,  // The token this vault keep
+    pub vault_token: Pubkey, // PDA for this vault keep the token
+    pub synth_token: Pubkey,
+    pub percent: u64,
+}
+
+#[event]
+pub struct TransferPositionEvent {
+    pub vault: Pubkey,
+    pub from: Pubkey,
+    pub from_index: u64,
+    pub to: Pubkey,
+    pub to_index: u64,
+    pub deposit: u64,
+    pub borrow: u64,
+}
+
+#[event]
+pub struct DepositForEvent {
+    pub vault: Pubkey,
+    pub treasure: Pubkey,
+    pub payer: Pubkey,
+    pub beneficiary: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct DepositAndBorrowEvent {
+    pub vault: Pubkey,
+    pub treasure: Pubkey,
+    pub deposited: u64,
+    pub borrowed: u64,
+}
+
+#[event]
+pub struct RepayAndWithdrawEvent {
+    pub vault: Pubkey,
+    pub treasure: Pubkey,
+    pub repaid: u64,
+    pub withdrawn: u64,
+}
+
+#[event]
+pub struct LeverageEvent {
+    pub vault: Pubkey,
+    pub treasure: Pubkey,
+    pub borrowed: u64,
+    pub deposited: u64,
+}
+
+#[event]
+pub struct SetMintLimitEvent {
+    pub vault: Pubkey,
+    pub mint_limit: u64,
+    pub mint_window: i64,
+}
+
+#[event]
+pub struct SetAmmEvent {
+    pub vault: Pubkey,
+    pub amm_program: Pubkey,
+}
+
+#[event]
+pub struct LossRecordedEvent {
+    pub vault: Pubkey,
+    pub loss: u64,
+    pub total_deposit: u64,
+    pub deposit_index: u128,
+}
+
+#[event]
+pub struct LiquidateToPoolEvent {
+    pub vault: Pubkey,
+    pub treasure: Pubkey,
+    pub debt: u64,
+    pub collateral: u64,
+}
+
+#[event]
+pub struct CreateObligationEvent {
+    pub vault: Pubkey,
+    pub obligation: Pubkey,
+}
+
+#[event]
+pub struct UpdateVaultEvent {
+    pub vault: Pubkey,
+    pub percent: u64,
+}
+
+#[event]
+pub struct FundRewardsEvent {
+    pub vault: Pubkey,
+    pub amount: u64,
+    pub reward_rate: u64,
+    pub reward_remaining: u64,
+}
+
+#[event]
+pub struct ReferralEvent {
+    pub vault: Pubkey,
+    pub treasure: Pubkey,
+    pub referrer: Pubkey,
+}
+
+#[event]
+pub struct ClaimReferralFeesEvent {
+    pub vault: Pubkey,
+    pub referrer: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct ClaimRewardsEvent {
+    pub vault: Pubkey,
+    pub treasure: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct SetPermissionedEvent {
+    pub vault: Pubkey,
+    pub permissioned: bool,
+}
+
+#[event]
+pub struct AllowListEvent {
+    pub vault: Pubkey,
+    pub wallet: Pubkey,
+    pub allowed: bool,
+}
+
+#[event]
+pub struct SetTimelockEvent {
+    pub vault: Pubkey,
+    pub timelock_delay: i64,
+}
+
+#[event]
+pub struct UpdateQueuedEvent {
+    pub vault: Pubkey,
+    pub percent: u64,
+    pub borrow_fee_bps: u64,
+    pub interest_rate: u64,
+    pub treasury: Pubkey,
+    pub timelock_delay: i64,
+    pub eta: i64,
+}
+
+#[event]
+pub struct UpdateExecutedEvent {
+    pub vault: Pubkey,
+    pub percent: u64,
+    pub borrow_fee_bps: u64,
+    pub interest_rate: u64,
+    pub treasury: Pubkey,
+    pub timelock_delay: i64,
+}
+
+#[event]
+pub struct UpdateCancelledEvent {
+    pub vault: Pubkey,
+}
+
+#[event]
+pub struct UpdateFeesEvent {
+    pub vault: Pubkey,
+    pub borrow_fee_bps: u64,
+    pub interest_rate: u64,
+    pub treasury: Pubkey,
+}
+
+#[event]
+pub struct FlashLoanEvent {
+    pub vault: Pubkey,
+    pub amount: u64,
+    pub fee: u64,
+}
+
+#[event]
+pub struct FlashMintEvent {
+    pub vault: Pubkey,
+    pub amount: u64,
+    pub fee: u64,
+}
+
+#[event]
+pub struct CloseVaultEvent {
+    pub vault: Pubkey,
+    pub swept: u64,
+    pub swept_reward: u64,
+}
+
+#[event]
+pub struct SynthMetadataEvent {
+    pub vault: Pubkey,
+    pub name: String,
+    pub symbol: String,
+    pub uri: String,
 }
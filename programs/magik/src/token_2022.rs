@@ -0,0 +1,204 @@
+use anchor_lang::prelude::*;
+use solana_program::instruction::Instruction;
+use solana_program::program::{get_return_data, invoke, invoke_signed};
+use solana_program::program_pack::Pack;
+use solana_program::system_instruction::create_account;
+use spl_token::state::{Account as SplAccount, Mint as SplMint};
+
+use crate::VaultError;
+
+pub mod token_2022_program {
+    solana_program::declare_id!("TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb");
+}
+
+// Token-2022 keeps the SPL Token instruction numbering
+pub const CLOSE_ACCOUNT: u8 = 9;
+pub const TRANSFER_CHECKED: u8 = 12;
+pub const INITIALIZE_ACCOUNT_3: u8 = 18;
+pub const GET_ACCOUNT_DATA_SIZE: u8 = 21;
+
+// Token-2022 accounts with extensions carry their type right after the base account length
+pub const ACCOUNT_TYPE_OFFSET: usize = SplAccount::LEN;
+pub const ACCOUNT_TYPE_MINT: u8 = 1;
+pub const ACCOUNT_TYPE_ACCOUNT: u8 = 2;
+
+pub fn is_token_program(program: &Pubkey) -> bool {
+    *program == spl_token::ID || *program == token_2022_program::ID
+}
+
+/// Base state of an SPL Token or Token-2022 account, extensions are ignored.
+pub fn unpack_account(info: &AccountInfo) -> std::result::Result<SplAccount, ProgramError> {
+    if !is_token_program(info.owner) {
+        return Err(VaultError::InvalidTokenAccount.into());
+    }
+    let data = info.try_borrow_data()?;
+    if data.len() < SplAccount::LEN {
+        return Err(VaultError::InvalidTokenAccount.into());
+    }
+    // An extended mint is just as long, only its account type tells them apart
+    if data.len() > SplAccount::LEN && data[ACCOUNT_TYPE_OFFSET] != ACCOUNT_TYPE_ACCOUNT {
+        return Err(VaultError::InvalidTokenAccount.into());
+    }
+    SplAccount::unpack_from_slice(&data[..SplAccount::LEN])
+}
+
+/// Base state of an SPL Token or Token-2022 mint, extensions are ignored.
+pub fn unpack_mint(info: &AccountInfo) -> std::result::Result<SplMint, ProgramError> {
+    if !is_token_program(info.owner) {
+        return Err(VaultError::InvalidTokenAccount.into());
+    }
+    let data = info.try_borrow_data()?;
+    if data.len() < SplMint::LEN {
+        return Err(VaultError::InvalidTokenAccount.into());
+    }
+    if data.len() > SplMint::LEN
+        && (data.len() <= ACCOUNT_TYPE_OFFSET || data[ACCOUNT_TYPE_OFFSET] != ACCOUNT_TYPE_MINT)
+    {
+        return Err(VaultError::InvalidTokenAccount.into());
+    }
+    SplMint::unpack_from_slice(&data[..SplMint::LEN])
+}
+
+/// Checks `info` is a token account of `mint`, returning its state.
+pub fn check_account(
+    info: &AccountInfo,
+    mint: &Pubkey,
+) -> std::result::Result<SplAccount, ProgramError> {
+    let account = unpack_account(info)?;
+    if account.mint != *mint {
+        return Err(VaultError::InvalidTokenAccount.into());
+    }
+    Ok(account)
+}
+
+/// `TransferChecked` works under both programs, and is the only transfer
+/// Token-2022 accepts for mints with a transfer fee.
+pub fn transfer_checked<'a, 'b, 'c, 'info>(
+    ctx: CpiContext<'a, 'b, 'c, 'info, TransferChecked<'info>>,
+    amount: u64,
+    decimals: u8,
+) -> ProgramResult {
+    let mut data = vec![TRANSFER_CHECKED];
+    data.extend_from_slice(&amount.to_le_bytes());
+    data.push(decimals);
+
+    let ix = Instruction {
+        program_id: ctx.program.key(),
+        accounts: vec![
+            AccountMeta::new(ctx.accounts.from.key(), false),
+            AccountMeta::new_readonly(ctx.accounts.mint.key(), false),
+            AccountMeta::new(ctx.accounts.to.key(), false),
+            AccountMeta::new_readonly(ctx.accounts.authority.key(), true),
+        ],
+        data,
+    };
+
+    invoke_signed(
+        &ix,
+        &[
+            ctx.accounts.from,
+            ctx.accounts.mint,
+            ctx.accounts.to,
+            ctx.accounts.authority,
+            ctx.program,
+        ],
+        ctx.signer_seeds,
+    )
+}
+
+#[derive(Accounts)]
+pub struct TransferChecked<'info> {
+    pub from: AccountInfo<'info>,
+    pub mint: AccountInfo<'info>,
+    pub to: AccountInfo<'info>,
+    pub authority: AccountInfo<'info>,
+}
+
+/// Creates the token account at a PDA, sized for whatever extensions `mint` requires.
+pub fn create_account_for_mint<'a, 'b, 'c, 'info>(
+    ctx: CpiContext<'a, 'b, 'c, 'info, CreateTokenAccount<'info>>,
+    rent: &Rent,
+) -> ProgramResult {
+    let program_id = ctx.program.key();
+    invoke(
+        &Instruction {
+            program_id,
+            accounts: vec![AccountMeta::new_readonly(ctx.accounts.mint.key(), false)],
+            data: vec![GET_ACCOUNT_DATA_SIZE],
+        },
+        &[ctx.accounts.mint.clone(), ctx.program.clone()],
+    )?;
+    let space = match get_return_data() {
+        Some((program, data)) if program == program_id && data.len() == 8 => {
+            let mut size = [0u8; 8];
+            size.copy_from_slice(&data);
+            u64::from_le_bytes(size)
+        }
+        _ => return Err(VaultError::InvalidTokenAccount.into()),
+    };
+
+    invoke_signed(
+        &create_account(
+            ctx.accounts.payer.key,
+            ctx.accounts.account.key,
+            rent.minimum_balance(space as usize),
+            space,
+            &program_id,
+        ),
+        &[
+            ctx.accounts.payer.clone(),
+            ctx.accounts.account.clone(),
+            ctx.accounts.system_program.clone(),
+        ],
+        ctx.signer_seeds,
+    )?;
+
+    let mut data = vec![INITIALIZE_ACCOUNT_3];
+    data.extend_from_slice(ctx.accounts.owner.key.as_ref());
+    invoke(
+        &Instruction {
+            program_id,
+            accounts: vec![
+                AccountMeta::new(ctx.accounts.account.key(), false),
+                AccountMeta::new_readonly(ctx.accounts.mint.key(), false),
+            ],
+            data,
+        },
+        &[ctx.accounts.account, ctx.accounts.mint, ctx.program],
+    )
+}
+
+#[derive(Accounts)]
+pub struct CreateTokenAccount<'info> {
+    pub payer: AccountInfo<'info>,
+    pub account: AccountInfo<'info>,
+    pub mint: AccountInfo<'info>,
+    pub owner: AccountInfo<'info>,
+    pub system_program: AccountInfo<'info>,
+}
+
+/// `CloseAccount` under whichever token program owns `account`.
+pub fn close_account<'a, 'b, 'c, 'info>(
+    ctx: CpiContext<'a, 'b, 'c, 'info, anchor_spl::token::CloseAccount<'info>>,
+) -> ProgramResult {
+    let ix = Instruction {
+        program_id: ctx.program.key(),
+        accounts: vec![
+            AccountMeta::new(ctx.accounts.account.key(), false),
+            AccountMeta::new(ctx.accounts.destination.key(), false),
+            AccountMeta::new_readonly(ctx.accounts.authority.key(), true),
+        ],
+        data: vec![CLOSE_ACCOUNT],
+    };
+
+    invoke_signed(
+        &ix,
+        &[
+            ctx.accounts.account,
+            ctx.accounts.destination,
+            ctx.accounts.authority,
+            ctx.program,
+        ],
+        ctx.signer_seeds,
+    )
+}
@@ -0,0 +1,164 @@
+use anchor_lang::accounts::program_account::ProgramAccount;
+use anchor_lang::prelude::*;
+use anchor_spl::token::TokenAccount;
+use port_variable_rate_lending_instructions::state::Reserve;
+use solana_program::program_pack::Pack;
+
+use crate::state::Vault;
+use crate::VaultError;
+
+/// Snapshot of what a vault holds, expressed in `mint_token` units.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Valuation {
+    pub idle: u64,             // Liquidity sitting in vault_token
+    pub collateral: u64,       // Port collateral held by the vault
+    pub collateral_value: u64, // Liquidity that collateral redeems to
+}
+
+impl Valuation {
+    pub fn total_assets(&self) -> std::result::Result<u64, ProgramError> {
+        self.idle
+            .checked_add(self.collateral_value)
+            .ok_or_else(|| VaultError::MathOverflow.into())
+    }
+
+    /// How far the vault's assets fell below `total_deposit`, zero while they cover it.
+    pub fn shortfall(&self, total_deposit: u64) -> std::result::Result<u64, ProgramError> {
+        Ok(total_deposit.saturating_sub(self.total_assets()?))
+    }
+
+    /// Share of the vault's Port yield earned by a position holding `deposit`.
+    /// This is what pays the position's debt down over time.
+    pub fn accrued_yield(
+        &self,
+        total_deposit: u64,
+        deposit: u64,
+    ) -> std::result::Result<u64, ProgramError> {
+        if total_deposit == 0 {
+            return Ok(0);
+        }
+        let total_yield = self.total_assets()?.saturating_sub(total_deposit);
+        Ok((total_yield as u128 * deposit as u128 / total_deposit as u128) as u64)
+    }
+}
+
+/// Cuts every deposit in `vault` by its share of the valuation's shortfall, returns the loss.
+///
+/// Only the vault-wide deposit index moves here, positions catch up on their next interaction.
+pub fn record_loss(
+    vault: &mut Vault,
+    valuation: &Valuation,
+) -> std::result::Result<u64, ProgramError> {
+    let loss = valuation.shortfall(vault.total_deposit)?;
+    if loss == 0 {
+        return Ok(0);
+    }
+    let remaining = vault.total_deposit - loss;
+    let index = vault.current_deposit_index() * remaining as u128 / vault.total_deposit as u128;
+    // Zero would read as 1.0, a vault losing everything keeps the smallest index instead
+    vault.deposit_index = index.max(1);
+    vault.total_deposit = remaining;
+    Ok(loss)
+}
+
+/// Grows every deposit in `vault` by its share of `gain`, liquidity that arrived in vault_token.
+///
+/// Like losses this only moves the deposit index. With nothing deposited the gain stays idle.
+pub fn record_gain(vault: &mut Vault, gain: u64) -> ProgramResult {
+    if gain == 0 || vault.total_deposit == 0 {
+        return Ok(());
+    }
+    let grown = vault
+        .total_deposit
+        .checked_add(gain)
+        .ok_or(VaultError::MathOverflow)?;
+    vault.deposit_index = vault
+        .current_deposit_index()
+        .checked_mul(grown as u128)
+        .ok_or(VaultError::MathOverflow)?
+        / vault.total_deposit as u128;
+    vault.total_deposit = grown;
+    Ok(())
+}
+
+/// Unpack the Port reserve backing `vault` and make sure it is the one the vault was set up with.
+pub fn load_reserve(
+    vault: &Vault,
+    reserve_info: &AccountInfo,
+    clock: &Clock,
+) -> std::result::Result<Reserve, ProgramError> {
+    if *reserve_info.owner != vault.lending_program {
+        return Err(VaultError::InvalidReserve.into());
+    }
+
+    let reserve = Reserve::unpack(&reserve_info.data.borrow())?;
+    if reserve.lending_market != vault.lending_market
+        || reserve.liquidity.mint_pubkey != vault.mint_token
+    {
+        return Err(VaultError::InvalidReserve.into());
+    }
+
+    // Exchange rate only moves forward once Port accrues interest, require a refresh in this slot
+    if reserve.last_update.is_stale(clock.slot)? {
+        return Err(VaultError::StaleReserve.into());
+    }
+
+    Ok(reserve)
+}
+
+/// Liquidity `collateral_amount` of reserve collateral redeems to.
+///
+/// The rate is `collateral mint supply / (available + borrowed liquidity)`.
+pub fn collateral_to_liquidity(
+    reserve: &Reserve,
+    collateral_amount: u64,
+) -> std::result::Result<u64, ProgramError> {
+    reserve
+        .collateral_exchange_rate()?
+        .collateral_to_liquidity(collateral_amount)
+}
+
+pub fn value_vault<'info>(
+    vault: &ProgramAccount<'info, Vault>,
+    vault_token: &AccountInfo<'info>,
+    collateral: &AccountInfo<'info>,
+    reserve_info: &AccountInfo<'info>,
+    clock: &Clock,
+) -> std::result::Result<Valuation, ProgramError> {
+    if vault_token.key() != vault.vault_token || collateral.key() != vault.collateral {
+        return Err(VaultError::InvalidCollateral.into());
+    }
+    let reserve = load_reserve(vault, reserve_info, clock)?;
+    let idle = crate::token_2022::unpack_account(vault_token)?.amount;
+
+    // lending_crank pins the collateral before its first deposit, unpinned means nothing lent
+    if vault.collateral == Pubkey::default() {
+        return Ok(Valuation {
+            idle,
+            ..Valuation::default()
+        });
+    }
+    let collateral = load_collateral(vault.key(), collateral, &reserve)?;
+
+    Ok(Valuation {
+        idle,
+        collateral: collateral.amount,
+        collateral_value: collateral_to_liquidity(&reserve, collateral.amount)?,
+    })
+}
+
+/// Unpack an account of the reserve's collateral mint owned by `vault`.
+pub fn load_collateral(
+    vault: Pubkey,
+    collateral_info: &AccountInfo,
+    reserve: &Reserve,
+) -> std::result::Result<TokenAccount, ProgramError> {
+    if *collateral_info.owner != spl_token::ID {
+        return Err(VaultError::InvalidCollateral.into());
+    }
+    let collateral = TokenAccount::try_deserialize(&mut &collateral_info.data.borrow()[..])?;
+    if collateral.mint != reserve.collateral.mint_pubkey || collateral.owner != vault {
+        return Err(VaultError::InvalidCollateral.into());
+    }
+    Ok(collateral)
+}
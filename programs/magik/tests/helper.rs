@@ -1,18 +1,12 @@
 #![cfg(feature = "test-bpf")]
 
 use anchor_lang::prelude::*;
-use anchor_lang::InstructionData;
 use anchor_spl::token::TokenAccount;
 use assert_matches::assert_matches;
-use magik_program;
 
 use solana_program::{program_pack::Pack, pubkey::Pubkey, system_instruction};
 use solana_sdk::{signature::Keypair, transport::Result};
-use {
-    solana_program::instruction::{AccountMeta, Instruction},
-    solana_program_test::*,
-    std::str::FromStr,
-};
+use {solana_program::instruction::Instruction, solana_program_test::*};
 
 use solana_sdk::{commitment_config::CommitmentLevel, signature::Signer, transaction::Transaction};
 
@@ -28,7 +22,7 @@ pub async fn process_and_assert_ok(
     all_signers.extend_from_slice(signers);
 
     let tx = Transaction::new_signed_with_payer(
-        &instructions,
+        instructions,
         Some(&payer.pubkey()),
         &all_signers,
         recent_blockhash,
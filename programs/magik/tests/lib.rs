@@ -1 +1,3 @@
+// Shared helpers, the test files use what they need
+#[allow(dead_code)]
 mod helper;
@@ -0,0 +1,80 @@
+#![cfg(feature = "test-bpf")]
+
+// Constant 1:1 AMM speaking the SPL Token Swap `Swap` layout, for leverage tests
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    program::{invoke, invoke_signed},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+pub fn authority(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"authority"], program_id)
+}
+
+pub fn process_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    data: &[u8],
+) -> ProgramResult {
+    if data.len() != 17 || data[0] != 1 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+    let mut amount = [0u8; 8];
+    amount.copy_from_slice(&data[1..9]);
+    let amount_in = u64::from_le_bytes(amount);
+    amount.copy_from_slice(&data[9..17]);
+    let minimum_amount_out = u64::from_le_bytes(amount);
+    if amount_in < minimum_amount_out {
+        return Err(ProgramError::Custom(0));
+    }
+
+    let iter = &mut accounts.iter();
+    let _swap = next_account_info(iter)?;
+    let swap_authority = next_account_info(iter)?;
+    let user_authority = next_account_info(iter)?;
+    let source = next_account_info(iter)?;
+    let swap_source = next_account_info(iter)?;
+    let swap_destination = next_account_info(iter)?;
+    let destination = next_account_info(iter)?;
+    let _pool_mint = next_account_info(iter)?;
+    let _pool_fee = next_account_info(iter)?;
+    let token_program = next_account_info(iter)?;
+
+    invoke(
+        &spl_token::instruction::transfer(
+            token_program.key,
+            source.key,
+            swap_source.key,
+            user_authority.key,
+            &[],
+            amount_in,
+        )?,
+        &[
+            source.clone(),
+            swap_source.clone(),
+            user_authority.clone(),
+            token_program.clone(),
+        ],
+    )?;
+
+    let (_, bump) = authority(program_id);
+    invoke_signed(
+        &spl_token::instruction::transfer(
+            token_program.key,
+            swap_destination.key,
+            destination.key,
+            swap_authority.key,
+            &[],
+            amount_in,
+        )?,
+        &[
+            swap_destination.clone(),
+            destination.clone(),
+            swap_authority.clone(),
+            token_program.clone(),
+        ],
+        &[&[b"authority", &[bump]]],
+    )
+}
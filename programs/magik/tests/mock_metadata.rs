@@ -0,0 +1,88 @@
+#![cfg(feature = "test-bpf")]
+
+// Token metadata program reduced to CreateMetadataAccountV3 and UpdateMetadataAccountV2, storing
+// the update authority followed by the DataV2, for synth metadata tests
+use anchor_lang::{AnchorDeserialize, AnchorSerialize};
+use magik_program::metadata::{DataV2, CREATE_METADATA_ACCOUNT_V3, UPDATE_METADATA_ACCOUNT_V2};
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    program::invoke_signed,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    rent::Rent,
+    system_instruction,
+    sysvar::Sysvar,
+};
+
+const METADATA_LEN: usize = 512;
+
+/// Update authority and data stored in a metadata account.
+pub fn unpack(data: &[u8]) -> (Pubkey, DataV2) {
+    let update_authority = Pubkey::new(&data[..32]);
+    let data = DataV2::deserialize(&mut &data[32..]).unwrap();
+    (update_authority, data)
+}
+
+fn write(metadata: &AccountInfo, update_authority: &Pubkey, data: &DataV2) -> ProgramResult {
+    let mut stored = update_authority.to_bytes().to_vec();
+    data.serialize(&mut stored)?;
+    let mut account = metadata.try_borrow_mut_data()?;
+    account.fill(0);
+    account[..stored.len()].copy_from_slice(&stored);
+    Ok(())
+}
+
+pub fn process_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    data: &[u8],
+) -> ProgramResult {
+    let (tag, mut rest) = data
+        .split_first()
+        .ok_or(ProgramError::InvalidInstructionData)?;
+    let iter = &mut accounts.iter();
+    let metadata = next_account_info(iter)?;
+
+    if *tag == CREATE_METADATA_ACCOUNT_V3 {
+        let mint = next_account_info(iter)?;
+        let mint_authority = next_account_info(iter)?;
+        let payer = next_account_info(iter)?;
+        let update_authority = next_account_info(iter)?;
+        let system_program = next_account_info(iter)?;
+        if !mint_authority.is_signer || !update_authority.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let seeds: &[&[u8]] = &[b"metadata", program_id.as_ref(), mint.key.as_ref()];
+        let (address, bump) = Pubkey::find_program_address(seeds, program_id);
+        if address != *metadata.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+        invoke_signed(
+            &system_instruction::create_account(
+                payer.key,
+                metadata.key,
+                Rent::get()?.minimum_balance(METADATA_LEN),
+                METADATA_LEN as u64,
+                program_id,
+            ),
+            &[payer.clone(), metadata.clone(), system_program.clone()],
+            &[&[b"metadata", program_id.as_ref(), mint.key.as_ref(), &[bump]]],
+        )?;
+        let data = DataV2::deserialize(&mut rest)?;
+        return write(metadata, update_authority.key, &data);
+    }
+
+    if *tag == UPDATE_METADATA_ACCOUNT_V2 {
+        let update_authority = next_account_info(iter)?;
+        let (stored_authority, _) = unpack(&metadata.try_borrow_data()?);
+        if !update_authority.is_signer || stored_authority != *update_authority.key {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let data = Option::<DataV2>::deserialize(&mut rest)?
+            .ok_or(ProgramError::InvalidInstructionData)?;
+        return write(metadata, update_authority.key, &data);
+    }
+
+    Err(ProgramError::InvalidInstructionData)
+}
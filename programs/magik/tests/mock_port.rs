@@ -0,0 +1,142 @@
+#![cfg(feature = "test-bpf")]
+
+// Port lending program reduced to RefreshReserve, DepositReserveLiquidity and
+// RedeemReserveCollateral over a reserve the test writes directly, for valuation tests
+use port_variable_rate_lending_instructions::state::Reserve;
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    clock::Clock,
+    entrypoint::ProgramResult,
+    program::{invoke, invoke_signed},
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    sysvar::Sysvar,
+};
+
+// Lending market authority, derived the way Port does
+pub fn authority(program_id: &Pubkey, lending_market: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[lending_market.as_ref()], program_id)
+}
+
+pub fn process_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    data: &[u8],
+) -> ProgramResult {
+    let (tag, rest) = data
+        .split_first()
+        .ok_or(ProgramError::InvalidInstructionData)?;
+    let iter = &mut accounts.iter();
+    if *tag == 3 {
+        let reserve_info = next_account_info(iter)?;
+        let mut reserve = Reserve::unpack(&reserve_info.data.borrow())?;
+        reserve.last_update.update_slot(Clock::get()?.slot);
+        Reserve::pack(reserve, &mut reserve_info.data.borrow_mut())?;
+        return Ok(());
+    }
+    if rest.len() != 8 || (*tag != 4 && *tag != 5) {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+    let mut amount = [0u8; 8];
+    amount.copy_from_slice(rest);
+    let amount = u64::from_le_bytes(amount);
+
+    let source = next_account_info(iter)?;
+    let destination = next_account_info(iter)?;
+    let reserve_info = next_account_info(iter)?;
+    let (supply, collateral_mint) = if *tag == 4 {
+        let supply = next_account_info(iter)?;
+        (supply, next_account_info(iter)?)
+    } else {
+        let collateral_mint = next_account_info(iter)?;
+        (next_account_info(iter)?, collateral_mint)
+    };
+    let lending_market = next_account_info(iter)?;
+    let market_authority = next_account_info(iter)?;
+    let user_authority = next_account_info(iter)?;
+    let _clock = next_account_info(iter)?;
+    let token_program = next_account_info(iter)?;
+
+    let mut reserve = Reserve::unpack(&reserve_info.data.borrow())?;
+    let rate = reserve.collateral_exchange_rate()?;
+    let (_, bump) = authority(program_id, lending_market.key);
+    let seeds: &[&[u8]] = &[lending_market.key.as_ref(), &[bump]];
+
+    if *tag == 4 {
+        let collateral = rate.liquidity_to_collateral(amount)?;
+        invoke(
+            &spl_token::instruction::transfer(
+                token_program.key,
+                source.key,
+                supply.key,
+                user_authority.key,
+                &[],
+                amount,
+            )?,
+            &[
+                source.clone(),
+                supply.clone(),
+                user_authority.clone(),
+                token_program.clone(),
+            ],
+        )?;
+        invoke_signed(
+            &spl_token::instruction::mint_to(
+                token_program.key,
+                collateral_mint.key,
+                destination.key,
+                market_authority.key,
+                &[],
+                collateral,
+            )?,
+            &[
+                collateral_mint.clone(),
+                destination.clone(),
+                market_authority.clone(),
+                token_program.clone(),
+            ],
+            &[seeds],
+        )?;
+        reserve.liquidity.available_amount += amount;
+        reserve.collateral.mint_total_supply += collateral;
+    } else {
+        let liquidity = rate.collateral_to_liquidity(amount)?;
+        invoke(
+            &spl_token::instruction::burn(
+                token_program.key,
+                source.key,
+                collateral_mint.key,
+                user_authority.key,
+                &[],
+                amount,
+            )?,
+            &[
+                source.clone(),
+                collateral_mint.clone(),
+                user_authority.clone(),
+                token_program.clone(),
+            ],
+        )?;
+        invoke_signed(
+            &spl_token::instruction::transfer(
+                token_program.key,
+                supply.key,
+                destination.key,
+                market_authority.key,
+                &[],
+                liquidity,
+            )?,
+            &[
+                supply.clone(),
+                destination.clone(),
+                market_authority.clone(),
+                token_program.clone(),
+            ],
+            &[seeds],
+        )?;
+        reserve.liquidity.available_amount -= liquidity;
+        reserve.collateral.mint_total_supply -= amount;
+    }
+    Reserve::pack(reserve, &mut reserve_info.data.borrow_mut())
+}
@@ -0,0 +1,112 @@
+#![cfg(feature = "test-bpf")]
+
+// Token-2022 reduced to TransferChecked and CloseAccount over extended accounts, which carry
+// their account type right after the base SPL layout, for Token-2022 vault tests
+use magik_program::token_2022::{
+    ACCOUNT_TYPE_ACCOUNT, ACCOUNT_TYPE_MINT, ACCOUNT_TYPE_OFFSET, CLOSE_ACCOUNT, TRANSFER_CHECKED,
+};
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+};
+use spl_token::state::{Account, Mint};
+
+/// Extended mint data, the base mint padded to the account type.
+pub fn pack_mint(mint: Mint) -> Vec<u8> {
+    let mut data = vec![0; ACCOUNT_TYPE_OFFSET + 1];
+    mint.pack_into_slice(&mut data[..Mint::LEN]);
+    data[ACCOUNT_TYPE_OFFSET] = ACCOUNT_TYPE_MINT;
+    data
+}
+
+/// Extended token account data, the base account followed by its account type.
+pub fn pack_account(account: Account) -> Vec<u8> {
+    let mut data = vec![0; ACCOUNT_TYPE_OFFSET + 1];
+    account.pack_into_slice(&mut data[..Account::LEN]);
+    data[ACCOUNT_TYPE_OFFSET] = ACCOUNT_TYPE_ACCOUNT;
+    data
+}
+
+fn unpack_account(
+    program_id: &Pubkey,
+    info: &AccountInfo,
+) -> std::result::Result<Account, ProgramError> {
+    let data = info.try_borrow_data()?;
+    if info.owner != program_id
+        || data.len() <= ACCOUNT_TYPE_OFFSET
+        || data[ACCOUNT_TYPE_OFFSET] != ACCOUNT_TYPE_ACCOUNT
+    {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    Account::unpack_from_slice(&data[..Account::LEN])
+}
+
+fn check_authority(account: &Account, authority: &AccountInfo) -> ProgramResult {
+    if !authority.is_signer || account.owner != *authority.key {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    Ok(())
+}
+
+pub fn process_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    data: &[u8],
+) -> ProgramResult {
+    let iter = &mut accounts.iter();
+    match data.first() {
+        Some(&TRANSFER_CHECKED) if data.len() == 10 => {
+            let mut amount = [0u8; 8];
+            amount.copy_from_slice(&data[1..9]);
+            let amount = u64::from_le_bytes(amount);
+
+            let from_info = next_account_info(iter)?;
+            let mint_info = next_account_info(iter)?;
+            let to_info = next_account_info(iter)?;
+            let authority = next_account_info(iter)?;
+            let mut from = unpack_account(program_id, from_info)?;
+            let to = unpack_account(program_id, to_info)?;
+            let mint = Mint::unpack_from_slice(&mint_info.try_borrow_data()?[..Mint::LEN])?;
+            if mint_info.owner != program_id
+                || from.mint != *mint_info.key
+                || to.mint != *mint_info.key
+                || mint.decimals != data[9]
+            {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            check_authority(&from, authority)?;
+
+            from.amount = from
+                .amount
+                .checked_sub(amount)
+                .ok_or(ProgramError::InsufficientFunds)?;
+            from.pack_into_slice(&mut from_info.try_borrow_mut_data()?[..Account::LEN]);
+            let mut to = if from_info.key == to_info.key {
+                from
+            } else {
+                to
+            };
+            to.amount += amount;
+            to.pack_into_slice(&mut to_info.try_borrow_mut_data()?[..Account::LEN]);
+            Ok(())
+        }
+        Some(&CLOSE_ACCOUNT) => {
+            let account_info = next_account_info(iter)?;
+            let destination = next_account_info(iter)?;
+            let authority = next_account_info(iter)?;
+            let account = unpack_account(program_id, account_info)?;
+            check_authority(&account, authority)?;
+            if account.amount != 0 {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            **destination.try_borrow_mut_lamports()? += account_info.lamports();
+            **account_info.try_borrow_mut_lamports()? = 0;
+            account_info.try_borrow_mut_data()?.fill(0);
+            Ok(())
+        }
+        _ => Err(ProgramError::InvalidInstructionData),
+    }
+}